@@ -0,0 +1,397 @@
+//! Portable single file copies of a log
+//!
+//! # Usage
+//! LogSync needs access to both repositories at the same time.  A bundle
+//! instead puts all objects which are reachable from the head of a branch
+//! into one file which can be carried to another machine and imported
+//! there.
+//!
+//! A bundle consists of:
+//!
+//! * The magic bytes `TBDB` and the format version as u32.
+//! * The length and the UTF-8 bytes of the branch name.
+//! * The head hash of the branch.
+//! * The number of anchors as u32 and their hashes.  A bundle of a
+//!   shallow log contains the anchors where its history ends.  Version 1
+//!   bundles have no anchors.
+//! * The number of records as u32.
+//! * The records.  Like in a pack, each record contains the hash, the
+//!   length and the raw bytes of an object.  The childs of an object are
+//!   always stored before the object itself.
+//!
+//! The importer calculates the hash of every record before it's written
+//! to the store, so a damaged bundle never puts a wrong object into the
+//! store.  The branch is only moved after all objects were imported and
+//! the whole history of the new head is in the store.  A forced import
+//! keeps the old head like a forced sync does.
+
+use std::io::{Read, Write};
+use std::io;
+use hash::*;
+use hashio::*;
+use io::*;
+use iolog::*;
+use log::*;
+use sync::*;
+
+
+/// Bytes every bundle starts with.
+pub const BUNDLE_MAGIC: &'static [u8] = b"TBDB";
+
+/// Version of the bundle format which is written.
+///
+/// Version 2 adds the anchors.
+pub const BUNDLE_VERSION: u32 = 2;
+
+/// Result of an export or import.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BundleReport {
+    /// Branch stored in the bundle.
+    pub branch: String,
+
+    /// Head of the branch stored in the bundle.
+    pub head: Hash,
+
+    /// Number of objects in the bundle.
+    pub objects: usize,
+
+    /// Number of objects which were missing in the store.
+    ///
+    /// For an export it's always 0.
+    pub written: usize
+}
+
+/// Writes and reads bundles of a branch.
+///
+/// # Examples
+/// ```
+/// use tbd::bundle::*;
+/// use tbd::hashio::*;
+/// use tbd::iolog::*;
+/// use tbd::log::*;
+/// use tbd::store::*;
+///
+/// let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(MemoryStore::new())));
+/// let head = log.push("one".to_string());
+/// let mut bytes = Vec::new();
+/// Bundle::new().export::<String, _>(&log.hashio, &mut bytes).unwrap();
+///
+/// let other = HashIO::with_store(Box::new(MemoryStore::new()));
+/// let report = Bundle::new().import(&other, &mut bytes.as_slice()).unwrap();
+/// assert_eq!(head, report.head);
+/// assert_eq!(2, report.written);
+/// ```
+pub struct Bundle {
+    branch: String,
+    force: bool
+}
+
+impl Bundle {
+    /// Bundle of the main branch which refuses non fast forward imports.
+    pub fn new() -> Bundle {
+        Bundle {
+            branch: MAIN_BRANCH.to_string(),
+            force: false
+        }
+    }
+
+    /// Export the given branch instead of the main branch.
+    ///
+    /// An import always uses the branch stored in the bundle.
+    pub fn with_branch(mut self, name: &str) -> Bundle {
+        self.branch = name.to_string();
+        self
+    }
+
+    /// Move the branch on import even if entries of the store get lost.
+    ///
+    /// The old head is kept as `backups/<branch>/<time>` reference.
+    pub fn force(mut self) -> Bundle {
+        self.force = true;
+        self
+    }
+
+    /// Write all objects reachable from the branch head.
+    ///
+    /// All log items must contain values of type T.
+    ///
+    /// # Errors
+    /// Fails with BranchNotFound if the branch doesn't exist and with a
+    /// StorageError if an object could not be read or written.
+    pub fn export<T, W>(&self, hashio: &HashIO, write: &mut W) -> Result<BundleReport, LogError>
+            where T: Readable, W: Write {
+        let store = hashio.store();
+        let head = match try!(read_branch_head(store, &self.branch)) {
+            Some(hash) => hash,
+            None => return Err(LogError::BranchNotFound(self.branch.clone()))
+        };
+        let anchors = try!(stored_history_anchors(store, head));
+        let objects = try!(reachable_objects::<T>(store, head, &|_| false));
+        try!(write_header(&self.branch, &head, &anchors, objects.len(), write)
+            .map_err(|err| LogError::StorageError(head, HashIOError::from(err))));
+        for hash in objects.iter() {
+            let bytes = try!(store.get(hash).map_err(|err| LogError::from_hashio_error(*hash, err)));
+            try!(write_record(hash, &bytes, write)
+                .map_err(|err| LogError::StorageError(*hash, HashIOError::from(err))));
+        }
+        Ok(BundleReport {
+            branch: self.branch.clone(),
+            head: head,
+            objects: objects.len(),
+            written: 0
+        })
+    }
+
+    /// Store all objects of the bundle and move its branch to its head.
+    ///
+    /// The anchors of the bundle are added to the store unless their
+    /// entries exist already.
+    ///
+    /// # Errors
+    /// Fails with CorruptedEntry if an object doesn't match its hash, with
+    /// EntryNotFound if an entry of the history is neither in the bundle
+    /// nor in the store and with NotAncestor if the current head of the
+    /// branch is not in the history of the bundle and the import is not
+    /// forced.  The branch is not moved in these cases but the valid
+    /// objects read so far stay in the store.
+    pub fn import<R>(&self, hashio: &HashIO, read: &mut R) -> Result<BundleReport, LogError>
+            where R: Read {
+        let store = hashio.store();
+        let (branch, head, bundle_anchors, count) = try!(read_header(read)
+            .map_err(|err| LogError::StorageError(Hash::None, err)));
+        if !is_valid_branch_name(&branch) {
+            return Err(LogError::InvalidBranch(format!("Invalid branch name: {}", branch)));
+        }
+        let mut written = 0;
+        for _ in 0..count {
+            let (hash, bytes) = try!(read_record(read)
+                .map_err(|err| LogError::StorageError(Hash::None, err)));
+            let actual_hash = hash.algorithm().unwrap_or_default().hash_bytes(&bytes);
+            if actual_hash != hash {
+                return Err(LogError::CorruptedEntry {
+                    hash: hash,
+                    actual_hash: actual_hash
+                });
+            }
+            if !store.exists(&hash) {
+                try!(store.put(&hash, &bytes).map_err(|err| LogError::StorageError(hash, err)));
+                written += 1;
+            }
+        }
+        let mut anchors = try!(read_anchors(store));
+        anchors.extend(bundle_anchors.iter().filter(|anchor| !store.exists(anchor)));
+        try!(check_history(store, head, &anchors));
+
+        let old_head = try!(read_branch_head(store, &branch));
+        if let Some(lost_head) = try!(lost_branch_head(store, old_head, head)) {
+            if !self.force {
+                return Err(LogError::NotAncestor {
+                    hash: lost_head,
+                    head: head
+                });
+            }
+            try!(write_branch_backup(store, &branch, &lost_head));
+        }
+        for anchor in bundle_anchors.iter() {
+            if !store.exists(anchor) {
+                try!(write_anchor(store, anchor));
+            }
+        }
+        try!(write_branch_head(store, &branch, &head));
+        Ok(BundleReport {
+            branch: branch,
+            head: head,
+            objects: count as usize,
+            written: written
+        })
+    }
+}
+
+impl Default for Bundle {
+    fn default() -> Self {
+        Bundle::new()
+    }
+}
+
+
+fn write_header<W: Write>(branch: &str, head: &Hash, anchors: &[Hash], count: usize,
+                          write: &mut W) -> Result<usize, io::Error> {
+    try!(write.write_all(BUNDLE_MAGIC));
+    let mut size = BUNDLE_MAGIC.len();
+    size += try!(write_u32(BUNDLE_VERSION, write));
+    size += try!(write_u32(branch.len() as u32, write));
+    try!(write.write_all(branch.as_bytes()));
+    size += branch.len();
+    size += try!(write_hash(head, write));
+    size += try!(write_u32(anchors.len() as u32, write));
+    for anchor in anchors.iter() {
+        size += try!(write_hash(anchor, write));
+    }
+    size += try!(write_u32(count as u32, write));
+    Ok(size)
+}
+
+/// Branch, head, anchors and number of records.
+fn read_header<R: Read>(read: &mut R) -> Result<(String, Hash, Vec<Hash>, u32), HashIOError> {
+    let magic = try!(read_bytes(read, BUNDLE_MAGIC.len()));
+    if magic != BUNDLE_MAGIC {
+        return Err(HashIOError::Undefined("Not a bundle".to_string()));
+    }
+    let version = try!(read_u32(read));
+    if version == 0 || version > BUNDLE_VERSION {
+        return Err(HashIOError::UnsupportedVersion(version));
+    }
+    let len = try!(read_u32(read));
+    let branch = try!(String::from_utf8(try!(read_bytes(read, len as usize)))
+        .map_err(|err| HashIOError::ParseError(Box::new(err))));
+    let head = try!(read_hash(read));
+    let mut anchors = Vec::new();
+    if version >= 2 {
+        let anchor_count = try!(read_u32(read));
+        for _ in 0..anchor_count {
+            anchors.push(try!(read_hash(read)));
+        }
+    }
+    let count = try!(read_u32(read));
+    Ok((branch, head, anchors, count))
+}
+
+fn write_record<W: Write>(hash: &Hash, bytes: &[u8], write: &mut W) -> Result<usize, io::Error> {
+    let mut size = try!(write_hash(hash, write));
+    size += try!(write_u32(bytes.len() as u32, write));
+    try!(write.write_all(bytes));
+    size += bytes.len();
+    Ok(size)
+}
+
+fn read_record<R: Read>(read: &mut R) -> Result<(Hash, Vec<u8>), HashIOError> {
+    let hash = try!(read_hash(read));
+    let len = try!(read_u32(read));
+    let bytes = try!(read_bytes(read, len as usize));
+    Ok((hash, bytes))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::gc::*;
+    use super::super::hashio::*;
+    use super::super::iolog::*;
+    use super::super::log::*;
+    use super::super::store::*;
+    use super::*;
+
+    #[test]
+    fn bundle_test() {
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(MemoryStore::new())));
+        log.push("one".to_string());
+        log.create_branch("feature").unwrap();
+        log.switch_branch("feature").unwrap();
+        let head = log.push("two".to_string());
+        let mut bytes = Vec::new();
+        let report = Bundle::new().with_branch("feature")
+            .export::<String, _>(&log.hashio, &mut bytes).unwrap();
+        assert_eq!(4, report.objects);
+
+        // A damaged object is refused and the branch stays untouched
+        let store = MemoryStore::new();
+        let hashio = HashIO::with_store(Box::new(store.clone()));
+        let mut damaged = bytes.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        match Bundle::new().import(&hashio, &mut damaged.as_slice()) {
+            Err(LogError::CorruptedEntry { hash, .. }) => assert_eq!(head, hash),
+            result => panic!("Damaged bundle was accepted: {:?}", result)
+        }
+        assert_eq!(None, read_branch_head(&store, "feature").unwrap());
+
+        let report = Bundle::new().import(&hashio, &mut bytes.as_slice()).unwrap();
+        assert_eq!("feature", report.branch);
+        assert_eq!(1, report.written);
+        let mut other = IOLog::<String>::with_hashio(hashio);
+        other.switch_branch("feature").unwrap();
+        assert_eq!(Some(head), other.head_hash());
+        assert_eq!(None, verify_log(&other));
+
+        // Entries which are not in the bundle would get lost
+        let other_head = other.push("three".to_string());
+        assert_eq!(Err(LogError::NotAncestor { hash: other_head, head: head }),
+                   Bundle::new().import(&other.hashio, &mut bytes.as_slice()));
+        Bundle::new().force().import(&other.hashio, &mut bytes.as_slice()).unwrap();
+        assert_eq!(Some(head), read_branch_head(&store, "feature").unwrap());
+
+        // The lost head is kept for the garbage collection
+        let backups: Vec<String> = store.list_refs().unwrap().into_iter()
+            .filter(|name| name.starts_with("backups/feature/")).collect();
+        assert_eq!(1, backups.len());
+        assert_eq!(Some(other_head), store.read_ref(&backups[0]).unwrap());
+        assert_eq!(vec!["feature".to_string()], other.list_branches().unwrap());
+        let report = GarbageCollection::new().dry_run()
+            .collect::<String>(&other.hashio).unwrap();
+        assert_eq!(0, report.unreachable.len());
+    }
+
+    #[test]
+    fn shallow_bundle_test() {
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(MemoryStore::new())));
+        log.push("one".to_string());
+        let two = log.push("two".to_string());
+        log.push("three".to_string());
+        assert_eq!(vec![two], log.truncate(1).unwrap());
+        let mut bytes = Vec::new();
+        assert_eq!(2, Bundle::new().export::<String, _>(&log.hashio, &mut bytes).unwrap().objects);
+
+        // The imported log ends at the same anchor
+        let other = MemoryStore::new();
+        Bundle::new().import(&HashIO::with_store(Box::new(other.clone())), &mut bytes.as_slice())
+            .unwrap();
+        assert_eq!(vec![two], read_anchors(&other).unwrap().into_iter().collect::<Vec<_>>());
+        let other_log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(other.clone())));
+        assert_eq!(log.head_hash(), other_log.head_hash());
+        assert_eq!(None, verify_log(&other_log));
+    }
+
+    #[test]
+    fn bundle_version_test() {
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(MemoryStore::new())));
+        let head = log.push("one".to_string());
+        let mut bytes = Vec::new();
+        Bundle::new().export::<String, _>(&log.hashio, &mut bytes).unwrap();
+
+        // Version 1 has no anchors
+        let anchors_pos = BUNDLE_MAGIC.len() + 4 + 4 + MAIN_BRANCH.len() + 33;
+        let mut old_bytes = bytes.clone();
+        old_bytes.drain(anchors_pos..anchors_pos + 4);
+        old_bytes[BUNDLE_MAGIC.len()..BUNDLE_MAGIC.len() + 4].copy_from_slice(&[0, 0, 0, 1]);
+        let report = Bundle::new().import(&HashIO::with_store(Box::new(MemoryStore::new())),
+                                          &mut old_bytes.as_slice()).unwrap();
+        assert_eq!(head, report.head);
+
+        bytes[BUNDLE_MAGIC.len()..BUNDLE_MAGIC.len() + 4].copy_from_slice(&[0, 0, 0, 3]);
+        assert_eq!(Err(LogError::StorageError(Hash::None, HashIOError::UnsupportedVersion(3))),
+                   Bundle::new().import(&HashIO::with_store(Box::new(MemoryStore::new())),
+                                        &mut bytes.as_slice()));
+    }
+
+    #[test]
+    fn incomplete_bundle_test() {
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(MemoryStore::new())));
+        let one = log.push("one".to_string());
+        let two = log.push("two".to_string());
+        let store = log.hashio.store();
+
+        // Leave out the first entry
+        let objects = reachable_objects::<String>(store, two, &|_| false).unwrap();
+        let mut bytes = Vec::new();
+        write_header(MAIN_BRANCH, &two, &[], 2, &mut bytes).unwrap();
+        for hash in objects[2..].iter() {
+            write_record(hash, &store.get(hash).unwrap(), &mut bytes).unwrap();
+        }
+
+        let other = MemoryStore::new();
+        assert_eq!(Err(LogError::EntryNotFound(one)),
+                   Bundle::new().import(&HashIO::with_store(Box::new(other.clone())),
+                                        &mut bytes.as_slice()).map(|report| report.head));
+        assert_eq!(None, read_branch_head(&other, MAIN_BRANCH).unwrap());
+    }
+}
@@ -0,0 +1,213 @@
+//! In-memory cache for stored objects
+//!
+//! # Usage
+//! Iterating a log reads every item from the store, often more than once:
+//! LogIteratorRef asks for the entry and for its parent hash separately.
+//! HashIO can keep the raw bytes of recently read objects in an
+//! ObjectCache to avoid reading them from disk again.
+//!
+//! Since objects are addressed by their hash, their content never changes
+//! and the cache never has to be invalidated.  The only exception are
+//! deleted objects which may still be served from the cache until it's
+//! cleared.
+//!
+//! The cache is bounded by a number of objects and by their total size in
+//! bytes.  If one of the limits is exceeded, the least recently used
+//! objects are dropped.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use hash::*;
+
+
+/// Hit and miss counters of an ObjectCache.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CacheStats {
+    /// Number of lookups which were answered by the cache.
+    pub hits: u64,
+
+    /// Number of lookups which had to go to the store.
+    pub misses: u64,
+
+    /// Number of objects which were dropped to stay within the limits.
+    pub evictions: u64,
+
+    /// Number of objects currently in the cache.
+    pub entries: usize,
+
+    /// Total size of all objects currently in the cache.
+    pub bytes: usize
+}
+
+/// Least recently used cache for the raw bytes of objects.
+///
+/// # Examples
+/// ```
+/// use tbd::cache::*;
+/// use tbd::hash::*;
+///
+/// let mut cache = ObjectCache::new(2, 1024);
+/// let one = Hash::hash_bytes(b"one");
+/// cache.insert(one, b"one".to_vec());
+/// assert_eq!(Some(b"one".to_vec()), cache.get(&one).map(|bytes| (*bytes).clone()));
+/// assert_eq!(None, cache.get(&Hash::hash_bytes(b"two")));
+/// assert_eq!(1, cache.stats().hits);
+/// assert_eq!(1, cache.stats().misses);
+/// ```
+pub struct ObjectCache {
+    max_entries: usize,
+    max_bytes: usize,
+    entries: BTreeMap<Hash, (u64, Rc<Vec<u8>>)>,
+
+    /// Hashes ordered by their last access.
+    usage: BTreeMap<u64, Hash>,
+    tick: u64,
+    stats: CacheStats
+}
+
+impl ObjectCache {
+    /// Cache which keeps at most max_entries objects with max_bytes in total.
+    pub fn new(max_entries: usize, max_bytes: usize) -> ObjectCache {
+        ObjectCache {
+            max_entries: max_entries,
+            max_bytes: max_bytes,
+            entries: BTreeMap::new(),
+            usage: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default()
+        }
+    }
+
+    /// Cache which never keeps anything.
+    pub fn disabled() -> ObjectCache {
+        ObjectCache::new(0, 0)
+    }
+
+    /// True if the limits allow to keep any object.
+    pub fn is_enabled(&self) -> bool {
+        self.max_entries > 0 && self.max_bytes > 0
+    }
+
+    /// Look up the object and mark it as recently used.
+    pub fn get(&mut self, hash: &Hash) -> Option<Rc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(hash) {
+            Some(&mut (ref mut last_used, ref bytes)) => {
+                self.usage.remove(last_used);
+                self.usage.insert(tick, *hash);
+                *last_used = tick;
+                self.stats.hits += 1;
+                Some(bytes.clone())
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Add the object and drop the least recently used ones if required.
+    ///
+    /// Objects which are larger than the byte limit are not cached.
+    pub fn insert(&mut self, hash: Hash, bytes: Vec<u8>) -> Rc<Vec<u8>> {
+        let bytes = Rc::new(bytes);
+        if !self.is_enabled() || bytes.len() > self.max_bytes {
+            return bytes;
+        }
+        self.remove(&hash);
+        self.tick += 1;
+        self.usage.insert(self.tick, hash);
+        self.entries.insert(hash, (self.tick, bytes.clone()));
+        self.stats.entries += 1;
+        self.stats.bytes += bytes.len();
+        while self.stats.entries > self.max_entries || self.stats.bytes > self.max_bytes {
+            let oldest = match self.usage.keys().next() {
+                Some(tick) => self.usage[tick],
+                None => break
+            };
+            self.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+        bytes
+    }
+
+    /// Drop the object from the cache.
+    pub fn remove(&mut self, hash: &Hash) {
+        if let Some((last_used, bytes)) = self.entries.remove(hash) {
+            self.usage.remove(&last_used);
+            self.stats.entries -= 1;
+            self.stats.bytes -= bytes.len();
+        }
+    }
+
+    /// Drop all objects but keep the counters.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.usage.clear();
+        self.stats.entries = 0;
+        self.stats.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::hash::*;
+    use super::super::hashio::*;
+    use super::super::iolog::*;
+    use super::super::log::*;
+    use super::super::store::*;
+    use super::*;
+
+    #[test]
+    fn lru_test() {
+        let one = Hash::hash_bytes(b"one");
+        let two = Hash::hash_bytes(b"two");
+        let three = Hash::hash_bytes(b"three");
+
+        let mut cache = ObjectCache::new(2, 100);
+        cache.insert(one, vec![1; 10]);
+        cache.insert(two, vec![2; 10]);
+        // Use one, so two is the oldest
+        assert_eq!(true, cache.get(&one).is_some());
+        cache.insert(three, vec![3; 10]);
+        assert_eq!(true, cache.get(&two).is_none());
+        assert_eq!(true, cache.get(&one).is_some());
+        assert_eq!(CacheStats { hits: 2, misses: 1, evictions: 1, entries: 2, bytes: 20 },
+                   cache.stats());
+
+        // The byte limit drops everything else
+        cache.insert(two, vec![2; 95]);
+        assert_eq!(1, cache.stats().entries);
+        assert_eq!(95, cache.stats().bytes);
+        // Too large for the cache at all
+        cache.insert(one, vec![1; 101]);
+        assert_eq!(true, cache.get(&one).is_none());
+
+        let mut cache = ObjectCache::disabled();
+        cache.insert(one, vec![1]);
+        assert_eq!(0, cache.stats().entries);
+    }
+
+    #[test]
+    fn hashio_cache_test() {
+        let store = MemoryStore::new();
+        let mut log = IOLog::<String>::with_hashio(
+            HashIO::with_store(Box::new(store.clone())).with_cache(100, 1 << 20));
+        for i in 0..10 {
+            log.push(format!("entry {}", i));
+        }
+        log.hashio.clear_cache();
+        let first: Vec<String> = LogIteratorRef::from_log(&log).collect();
+        let misses = log.hashio.cache_stats().misses;
+        let second: Vec<String> = LogIteratorRef::from_log(&log).collect();
+        assert_eq!(first, second);
+        assert_eq!(misses, log.hashio.cache_stats().misses);
+        assert_eq!(true, log.hashio.cache_stats().hits > 0);
+    }
+}
@@ -0,0 +1,218 @@
+//! Signed checkpoints of a log head
+//!
+//! # Usage
+//! Signing every entry is not always wanted.  A Checkpoint is a cheaper
+//! way to sign a log: it contains the head hash, the number of entries
+//! and a timestamp and is signed as a whole.  IOLog writes a new
+//! checkpoint next to the `head` reference whenever it has a signing key.
+//!
+//! The checkpoint is stored as an object and the `checkpoint` reference
+//! points to the latest one.  When a log is opened with trusted keys, a
+//! head which doesn't match the latest valid checkpoint is refused.
+//!
+//! An older checkpoint stays validly signed, so somebody could replace
+//! the latest one together with the head to roll the log back.  Readers
+//! which remember the last checkpoint they trusted can detect this: a
+//! newer checkpoint must not have fewer entries or an earlier timestamp.
+
+extern crate time;
+
+use std::io;
+use std::io::{Read, Write};
+use self::time::Tm;
+use hash::*;
+use hashio::*;
+use io::*;
+use sign::*;
+
+
+/// Name of the reference which points to the latest checkpoint.
+pub const CHECKPOINT_REF: &'static str = "checkpoint";
+
+/// Signed state of a log at a point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub head_hash: Hash,
+
+    /// Number of entries from the first entry up to the head.
+    pub entry_count: u64,
+    pub timestamp: Tm,
+    pub signature: Option<EntrySignature>
+}
+
+impl Checkpoint {
+    /// Unsigned checkpoint of the head with the current time.
+    pub fn new(head_hash: Hash, entry_count: u64) -> Checkpoint {
+        Checkpoint {
+            head_hash: head_hash,
+            entry_count: entry_count,
+            timestamp: time::now_utc(),
+            signature: None
+        }
+    }
+
+    /// Hash of the checkpoint without its signature which is signed.
+    pub fn signed_hash(&self) -> Hash {
+        let mut bytes = Vec::new();
+        self.write_unsigned(&mut bytes).expect("Writing to a vec should not cause any issues");
+        Hash::hash_bytes(&bytes)
+    }
+
+    /// Sign the checkpoint with the key.
+    pub fn sign(mut self, key: &SigningKey) -> Checkpoint {
+        self.signature = Some(key.sign(&self.signed_hash()));
+        self
+    }
+
+    /// True if the checkpoint has a valid signature of a trusted key.
+    pub fn is_trusted(&self, trusted_keys: &[PublicKey]) -> bool {
+        match self.signature {
+            None => false,
+            Some(ref signature) => trusted_keys.contains(&signature.public_key)
+                && signature.verify(&self.signed_hash())
+        }
+    }
+
+    /// True if the checkpoint can follow the previously trusted one.
+    ///
+    /// Neither the number of entries nor the timestamp may go back.
+    pub fn follows(&self, previous: &Checkpoint) -> bool {
+        self.entry_count >= previous.entry_count && self.timestamp >= previous.timestamp
+    }
+
+    fn write_unsigned<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
+        let mut size = 0;
+        size += try!(write_hash(&self.head_hash, write));
+        size += try!(write_u64(self.entry_count, write));
+        size += try!(write_tm(self.timestamp, write));
+        Ok(size)
+    }
+}
+
+impl Writable for Checkpoint {
+    fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
+        let mut size = try!(self.write_unsigned(write));
+        match self.signature {
+            None => size += try!(write_u8(0, write)),
+            Some(ref signature) => {
+                size += try!(write_u8(1, write));
+                size += try!(signature.write_to(write));
+            }
+        }
+        Ok(size)
+    }
+}
+hashable_for_writable!(Checkpoint);
+
+impl Readable for Checkpoint {
+    fn read_from<R: Read, C: ChildReader>(read: &mut R, childs: &C)
+            -> Result<Checkpoint, HashIOError> {
+        let head_hash = try!(read_hash(read));
+        let entry_count = try!(read_u64(read));
+        let timestamp = try!(read_tm(read));
+        let signature = match try!(read_u8(read)) {
+            0 => None,
+            1 => Some(try!(EntrySignature::read_from(read, childs))),
+            marker => return Err(HashIOError::Undefined(
+                format!("Unknown signature marker: {}", marker)))
+        };
+        Ok(Checkpoint {
+            head_hash: head_hash,
+            entry_count: entry_count,
+            timestamp: timestamp,
+            signature: signature
+        })
+    }
+}
+hashio_for_readable!(Checkpoint);
+
+
+/// Store the checkpoint and let the checkpoint reference point to it.
+pub fn write_checkpoint(hashio: &HashIO, checkpoint: &Checkpoint) -> Result<(), HashIOError> {
+    try!(hashio.put(checkpoint));
+    hashio.store().write_ref(CHECKPOINT_REF, &checkpoint.as_hash_with(hashio.algorithm()))
+}
+
+/// Read the latest checkpoint.
+///
+/// Returns None if no checkpoint was written yet.
+pub fn read_checkpoint(hashio: &HashIO) -> Result<Option<Checkpoint>, HashIOError> {
+    match try!(hashio.store().read_ref(CHECKPOINT_REF)) {
+        None => Ok(None),
+        Some(hash) => Ok(Some(try!(hashio.get(&hash))))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::hash::*;
+    use super::super::hashio::*;
+    use super::super::iolog::*;
+    use super::super::log::*;
+    use super::super::sign::*;
+    use super::super::store::*;
+    use super::*;
+
+    #[test]
+    fn checkpoint_test() {
+        let store = MemoryStore::new();
+        let key = SigningKey::from_seed([5u8; 32]);
+        let trusted = [key.public_key()];
+        let head = {
+            let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())))
+                .with_signing_key(key.clone());
+            log.push("one".to_string());
+            log.push("two".to_string())
+        };
+        let hashio = HashIO::with_store(Box::new(store.clone()));
+        let checkpoint = read_checkpoint(&hashio).unwrap().unwrap();
+        assert_eq!(head, checkpoint.head_hash);
+        assert_eq!(2, checkpoint.entry_count);
+        assert_eq!(true, checkpoint.is_trusted(&trusted));
+        assert_eq!(false, checkpoint.is_trusted(&[SigningKey::from_seed([6u8; 32]).public_key()]));
+
+        let log = IOLog::<String>::with_hashio_checked(
+            HashIO::with_store(Box::new(store.clone())), &trusted).unwrap();
+        assert_eq!(Some(head), log.head_hash());
+
+        // A replayed older checkpoint is only detected with the last trusted one
+        let newer = {
+            let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())))
+                .with_signing_key(key.clone());
+            log.push("newer".to_string());
+            read_checkpoint(&log.hashio).unwrap().unwrap()
+        };
+        write_checkpoint(&hashio, &checkpoint).unwrap();
+        store.write_ref("head", &head).unwrap();
+        assert_eq!(true, IOLog::<String>::with_hashio_checked(
+            HashIO::with_store(Box::new(store.clone())), &trusted).is_ok());
+        match IOLog::<String>::with_hashio_checked_after(
+                HashIO::with_store(Box::new(store.clone())), &trusted, &newer) {
+            Err(LogError::InvalidCheckpoint(_)) => (),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Rolled back checkpoint was accepted")
+        }
+        assert_eq!(true, IOLog::<String>::with_hashio_checked_after(
+            HashIO::with_store(Box::new(store.clone())), &trusted, &checkpoint).is_ok());
+        let mut earlier = checkpoint.clone();
+        earlier.timestamp.tm_year -= 1;
+        assert_eq!(true, checkpoint.follows(&earlier));
+        assert_eq!(false, earlier.follows(&checkpoint));
+
+        // Somebody without the key moves the head
+        {
+            let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+            log.push("three".to_string());
+        }
+        match IOLog::<String>::with_hashio_checked(
+                HashIO::with_store(Box::new(store.clone())), &trusted) {
+            Err(LogError::InvalidCheckpoint(_)) => (),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Head without checkpoint was accepted")
+        }
+        // Unchecked logs still open
+        let log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        assert_eq!(Ok("three".to_string()), log.get(log.head_hash().unwrap()));
+    }
+}
@@ -0,0 +1,237 @@
+//! Integrity check of a HashIO repository
+//!
+//! # Usage
+//! `fsck` checks a repository directory which was written by a
+//! DirectoryStore.  It reads every object again and compares the hash of
+//! the stored bytes with the filename.  It also looks for leftovers of
+//! interrupted writes, references which can't be read and objects which
+//! are referenced by a log but are missing in the repository.
+//!
+//! Problems are collected in a FsckReport instead of stopping at the
+//! first one.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use checkpoint::CHECKPOINT_REF;
+use hash::*;
+use hashio::*;
+use iolog::*;
+use pack::*;
+use store::*;
+
+
+/// All problems found by fsck.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FsckReport {
+    /// Number of objects which were checked.
+    pub checked: usize,
+
+    /// Objects whose content doesn't match the filename.
+    ///
+    /// Contains the hash of the filename and the hash of the content.
+    pub hash_mismatches: Vec<(Hash, Hash)>,
+
+    /// Objects which couldn't be read and the error message.
+    pub unreadable_objects: Vec<(Hash, String)>,
+
+    /// Unfinished files of interrupted writes.
+    pub temp_files: Vec<String>,
+
+    /// References which couldn't be read or point to a missing object.
+    ///
+    /// Contains the name of the reference and the error message.
+    pub broken_refs: Vec<(String, String)>,
+
+    /// Missing objects which are required by a log entry.
+    ///
+    /// Contains the hash of the log entry and the missing hash.
+    pub dangling: Vec<(Hash, Hash)>
+}
+
+impl FsckReport {
+    /// True if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.hash_mismatches.is_empty()
+            && self.unreadable_objects.is_empty()
+            && self.temp_files.is_empty()
+            && self.broken_refs.is_empty()
+            && self.dangling.is_empty()
+    }
+}
+
+
+/// Store which remembers all hashes which couldn't be read.
+struct RecordingStore {
+    store: Box<ObjectStore>,
+    missing: Rc<RefCell<BTreeSet<Hash>>>
+}
+
+impl ObjectStore for RecordingStore {
+    fn get(&self, hash: &Hash) -> Result<Vec<u8>, HashIOError> {
+        let result = self.store.get(hash);
+        if result.is_err() {
+            self.missing.borrow_mut().insert(*hash);
+        }
+        result
+    }
+
+    fn put(&self, hash: &Hash, data: &[u8]) -> Result<(), HashIOError> {
+        self.store.put(hash, data)
+    }
+
+    fn exists(&self, hash: &Hash) -> bool {
+        self.store.exists(hash)
+    }
+
+    fn list(&self) -> Result<Vec<Hash>, HashIOError> {
+        self.store.list()
+    }
+
+    fn delete(&self, hash: &Hash) -> Result<(), HashIOError> {
+        self.store.delete(hash)
+    }
+
+    fn read_ref(&self, name: &str) -> Result<Option<Hash>, HashIOError> {
+        self.store.read_ref(name)
+    }
+
+    fn write_ref(&self, name: &str, hash: &Hash) -> Result<(), HashIOError> {
+        self.store.write_ref(name, hash)
+    }
+
+    fn list_refs(&self) -> Result<Vec<String>, HashIOError> {
+        self.store.list_refs()
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<(), HashIOError> {
+        self.store.delete_ref(name)
+    }
+
+    fn flush(&self) -> Result<(), HashIOError> {
+        self.store.flush()
+    }
+}
+
+
+/// Check the repository in the given directory.
+///
+/// The objects of its pack are checked if the directory has one.
+///
+/// All references are expected to point to IOLogItems of type T.  Their
+/// history is walked to find missing objects.
+///
+/// # Errors
+/// Only fails if the repository directory itself can't be read.
+pub fn fsck<T>(path: String) -> Result<FsckReport, HashIOError>
+        where T: Hashable,
+              HashIO: HashIOImpl<T> {
+    let store = try!(open_store(path.clone()));
+    let mut report = FsckReport::default();
+
+    // Objects
+    for hash in try!(store.list()) {
+        report.checked += 1;
+        match store.get(&hash) {
+            Ok(bytes) => {
+                let algorithm = hash.algorithm().unwrap_or_default();
+                let actual = algorithm.hash_bytes(&bytes);
+                if actual != hash {
+                    report.hash_mismatches.push((hash, actual));
+                }
+            },
+            Err(err) => report.unreadable_objects.push((hash, format!("{}", err)))
+        }
+    }
+    report.temp_files = try!(DirectoryStore::new(path.clone()).temp_files());
+
+    // References and the history of the logs
+    let missing = Rc::new(RefCell::new(BTreeSet::new()));
+    let hashio = HashIO::with_store(Box::new(RecordingStore {
+        store: try!(open_store(path)),
+        missing: missing.clone()
+    }));
+    // The history of a shallow log ends at its anchors
+    let mut visited = try!(read_anchors(&*store));
+    for name in try!(store.list_refs()) {
+        if name.starts_with(ANCHOR_REF_PREFIX) {
+            continue;
+        }
+        let mut hash = match store.read_ref(&name) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => continue,
+            Err(err) => {
+                report.broken_refs.push((name, format!("{}", err)));
+                continue;
+            }
+        };
+        if !store.exists(&hash) {
+            report.broken_refs.push((name, format!("Object not found: {}", hash.as_string())));
+            continue;
+        }
+        if name == CHECKPOINT_REF {
+            continue;
+        }
+        let mut pending = vec![hash];
+        while let Some(hash) = pending.pop() {
+            if hash == Hash::None || visited.contains(&hash) {
+                continue;
+            }
+            visited.insert(hash);
+            match hashio.get::<IOLogItem<T>>(&hash) {
+                Ok(item) => pending.extend(item.parent_hashes()),
+                Err(_) => {
+                    for missing_hash in missing.borrow().iter() {
+                        report.dangling.push((hash, *missing_hash));
+                    }
+                    missing.borrow_mut().clear();
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::hash::*;
+    use super::super::hashio::*;
+    use super::super::store::*;
+    use super::super::iolog::*;
+    use super::super::log::*;
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn fsck_test() {
+        let dir = TestDirectory::new("fscktest");
+        let one_hash;
+        let two_hash;
+        {
+            let mut log = IOLog::<String>::new(dir.path.clone());
+            one_hash = log.push("one".to_string());
+            two_hash = log.push("two".to_string());
+        }
+        let report = fsck::<String>(dir.path.clone()).unwrap();
+        assert_eq!(true, report.is_ok());
+        assert_eq!(4, report.checked);
+
+        let store = DirectoryStore::new(dir.path.clone());
+        // Corrupt the first item, drop the string of the second one and leave a temp file
+        let two_string = "two".to_string().as_hash();
+        store.delete(&two_string).unwrap();
+        File::create(store.filename_for_hash(&one_hash)).unwrap().write_all(b"bad").unwrap();
+        File::create(format!("{}_", store.filename_for_hash(&two_string))).unwrap();
+        store.write_ref("head-broken", &Hash::hash_bytes(b"missing")).unwrap();
+
+        let report = fsck::<String>(dir.path.clone()).unwrap();
+        assert_eq!(false, report.is_ok());
+        assert_eq!(vec![(one_hash, Hash::hash_bytes(b"bad"))], report.hash_mismatches);
+        assert_eq!(1, report.temp_files.len());
+        assert_eq!(vec![(two_hash, two_string)], report.dangling);
+        assert_eq!(1, report.broken_refs.len());
+        assert_eq!("head-broken", report.broken_refs[0].0);
+    }
+}
@@ -0,0 +1,269 @@
+//! Garbage collection of unreachable objects
+//!
+//! # Usage
+//! Resetting the head of a log or rebuilding it leaves objects in the
+//! store which are not reachable anymore.  GarbageCollection marks all
+//! objects which can be reached from a set of log heads and removes or
+//! reports all others.
+//!
+//! The roots are the hashes of IOLogItems.  By default all references
+//! of the store are used which includes the `head`, its
+//! `head-<timestamp>` backups, the `refs/<branch>` references and the
+//! `backups/<branch>/` references of heads which were overwritten by a
+//! forced sync.  The children of each item are found by reading the
+//! item and remembering every child hash which is requested on the way.
+//! The object of the `checkpoint` reference is kept as well.
+//!
+//! The anchors of a shallow log end the history, so the entries behind
+//! them are removed unless another root reaches them.
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use checkpoint::CHECKPOINT_REF;
+use hash::*;
+use hashio::*;
+use io::*;
+use iolog::{ANCHOR_REF_PREFIX, BACKUP_REF_PREFIX, read_anchors, read_item_links};
+use store::*;
+
+
+/// ChildReader which remembers every hash which was read.
+///
+/// Reading a value through it marks all of its childs by the hashes
+/// they are stored with.  This also works for values which were migrated
+/// from an older version and would calculate to different hashes now.
+/// Childs which are marked already are not read again if their type has
+/// a placeholder, so shared subtrees are only walked once.
+struct MarkReader<'a> {
+    store: &'a ObjectStore,
+    marked: RefCell<BTreeSet<Hash>>
+}
+
+impl<'a> MarkReader<'a> {
+    fn mark(&self, hash: &Hash) {
+        self.marked.borrow_mut().insert(*hash);
+    }
+
+    fn is_marked(&self, hash: &Hash) -> bool {
+        self.marked.borrow().contains(hash)
+    }
+}
+
+impl<'a> ChildReader for MarkReader<'a> {
+    fn read_child<T: Readable>(&self, hash: &Hash) -> Result<T, HashIOError> {
+        if self.is_marked(hash) {
+            if let Some(value) = T::placeholder() {
+                return Ok(value);
+            }
+        }
+        self.mark(hash);
+        let bytes = try!(self.store.get(hash));
+        let mut read: &[u8] = &bytes;
+        T::read_from(&mut read, self)
+    }
+}
+
+
+/// Result of a garbage collection run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GcReport {
+    /// Number of objects which are reachable from the roots.
+    pub reachable: usize,
+
+    /// All objects which are not reachable from the roots.
+    pub unreachable: Vec<Hash>,
+
+    /// True if the unreachable objects were removed from the store.
+    pub deleted: bool
+}
+
+/// Mark and sweep garbage collection for IOLog repositories.
+///
+/// # Examples
+/// ```
+/// use tbd::hashio::*;
+/// use tbd::store::*;
+/// use tbd::iolog::*;
+/// use tbd::log::*;
+/// use tbd::gc::*;
+///
+/// let store = MemoryStore::new();
+/// let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+/// log.push("one".to_string());
+/// // Throw away the head and its backups
+/// for name in store.list_refs().unwrap() {
+///     store.delete_ref(&name).unwrap();
+/// }
+///
+/// let report = GarbageCollection::new().dry_run()
+///         .collect::<String>(&log.hashio).unwrap();
+/// assert_eq!(0, report.reachable);
+/// assert_eq!(2, report.unreachable.len());
+/// assert_eq!(false, report.deleted);
+/// ```
+pub struct GarbageCollection {
+    roots: Vec<Hash>,
+    use_refs: bool,
+    use_backups: bool,
+    dry_run: bool
+}
+
+impl GarbageCollection {
+    /// Garbage collection which uses all references of the store as roots.
+    pub fn new() -> GarbageCollection {
+        GarbageCollection {
+            roots: Vec::new(),
+            use_refs: true,
+            use_backups: true,
+            dry_run: false
+        }
+    }
+
+    /// Add a log item hash which must be kept.
+    pub fn with_root(mut self, hash: Hash) -> GarbageCollection {
+        self.roots.push(hash);
+        self
+    }
+
+    /// Don't use the references of the store as roots.
+    ///
+    /// Only the roots added by with_root are kept.
+    pub fn without_refs(mut self) -> GarbageCollection {
+        self.use_refs = false;
+        self
+    }
+
+    /// Don't keep the `head-<timestamp>` and `backups/` references.
+    pub fn without_backups(mut self) -> GarbageCollection {
+        self.use_backups = false;
+        self
+    }
+
+    /// Only report the unreachable objects but don't delete them.
+    pub fn dry_run(mut self) -> GarbageCollection {
+        self.dry_run = true;
+        self
+    }
+
+    /// Collect all root hashes for the given store.
+    fn root_hashes(&self, store: &ObjectStore) -> Result<Vec<Hash>, HashIOError> {
+        let mut result = self.roots.clone();
+        if self.use_refs {
+            for name in try!(store.list_refs()) {
+                let is_backup = name.starts_with("head-") || name.starts_with(BACKUP_REF_PREFIX);
+                if (!self.use_backups && is_backup) || name == CHECKPOINT_REF
+                        || name.starts_with(ANCHOR_REF_PREFIX) {
+                    continue;
+                }
+                if let Some(hash) = try!(store.read_ref(&name)) {
+                    result.push(hash);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Mark all objects reachable from the roots and sweep the others.
+    ///
+    /// The roots must be IOLogItems of the type T.
+    ///
+    /// # Errors
+    /// Fails if a reachable object could not be read.  Nothing is deleted
+    /// in this case.
+    pub fn collect<T>(&self, hashio: &HashIO) -> Result<GcReport, HashIOError>
+            where T: Readable {
+        let store = hashio.store();
+        let marker = MarkReader {
+            store: store,
+            marked: RefCell::new(BTreeSet::new())
+        };
+
+        // Mark
+        let anchors = try!(read_anchors(store));
+        let mut pending = try!(self.root_hashes(store));
+        while let Some(hash) = pending.pop() {
+            if hash == Hash::None || marker.is_marked(&hash) || anchors.contains(&hash) {
+                continue;
+            }
+            marker.mark(&hash);
+            let bytes = try!(store.get(&hash));
+            let mut read: &[u8] = &bytes;
+            let (parents, item_hash) = try!(read_item_links(&mut read));
+            if !marker.is_marked(&item_hash) {
+                try!(marker.read_child::<T>(&item_hash));
+            }
+            pending.extend(parents);
+        }
+
+        // The latest checkpoint is no log item but must be kept
+        if self.use_refs {
+            if let Some(hash) = try!(store.read_ref(CHECKPOINT_REF)) {
+                marker.mark(&hash);
+            }
+        }
+
+        // Sweep
+        let marked = marker.marked.borrow();
+        let mut unreachable = Vec::new();
+        for hash in try!(store.list()) {
+            if !marked.contains(&hash) {
+                unreachable.push(hash);
+            }
+        }
+        if !self.dry_run {
+            for hash in unreachable.iter() {
+                try!(store.delete(hash));
+            }
+            try!(store.flush());
+            hashio.clear_cache();
+        }
+        Ok(GcReport {
+            reachable: marked.len(),
+            unreachable: unreachable,
+            deleted: !self.dry_run
+        })
+    }
+}
+
+impl Default for GarbageCollection {
+    fn default() -> Self {
+        GarbageCollection::new()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::hash::*;
+    use super::super::hashio::*;
+    use super::super::store::*;
+    use super::super::iolog::*;
+    use super::super::log::*;
+    use super::*;
+
+    #[test]
+    fn gc_test() {
+        let store = MemoryStore::new();
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        let one = log.push("one".to_string());
+        log.push("two".to_string());
+        log.reset_head(&one).unwrap();
+        let three = log.push("three".to_string());
+
+        // Only "two" is lost if the backups are ignored
+        let report = GarbageCollection::new().without_backups().dry_run()
+            .collect::<String>(&log.hashio).unwrap();
+        assert_eq!(2, report.unreachable.len());
+        assert_eq!(false, report.deleted);
+        assert_eq!(6, store.list().unwrap().len());
+
+        let report = GarbageCollection::new().without_refs().with_root(three)
+            .collect::<String>(&log.hashio).unwrap();
+        // Item and string of "two" are gone
+        assert_eq!(2, report.unreachable.len());
+        assert_eq!(4, report.reachable);
+        assert_eq!(4, store.list().unwrap().len());
+        assert_eq!(Ok("one".to_string()), log.get(one));
+        assert_eq!(Ok("three".to_string()), log.get(three));
+    }
+}
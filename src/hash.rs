@@ -2,14 +2,93 @@ extern crate crypto;
 extern crate byteorder;
 
 use self::crypto::sha3::Sha3;
+use self::crypto::sha2::Sha256;
+use self::crypto::blake2b::Blake2b;
 use self::crypto::digest::Digest;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
 
 
 /// Stores one of the supported hash values.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Hash {
     None,
-    Sha3([u8; 32])
+    Sha3([u8; 32]),
+    Blake2b([u8; 32]),
+    Sha256([u8; 32])
+}
+
+/// Algorithms which can be used to generate a Hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HashAlgorithm {
+    /// sha3-256, the default
+    Sha3,
+    /// blake2b with 256 bit output
+    Blake2b,
+    /// sha2-256
+    Sha256
+}
+
+impl HashAlgorithm {
+    /// Returns a hash of the byte array.
+    pub fn hash_bytes(&self, bytes: &[u8]) -> Hash {
+        let mut res = [0u8; 32];
+        match *self {
+            HashAlgorithm::Sha3 => {
+                let mut sha3 = Sha3::sha3_256();
+                sha3.input(bytes);
+                sha3.result(&mut res);
+                Hash::Sha3(res)
+            },
+            HashAlgorithm::Blake2b => {
+                let mut blake2b = Blake2b::new(32);
+                blake2b.input(bytes);
+                blake2b.result(&mut res);
+                Hash::Blake2b(res)
+            },
+            HashAlgorithm::Sha256 => {
+                let mut sha256 = Sha256::new();
+                sha256.input(bytes);
+                sha256.result(&mut res);
+                Hash::Sha256(res)
+            }
+        }
+    }
+
+    /// Short name of the algorithm.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::Sha3 => "sha3",
+            HashAlgorithm::Blake2b => "blake2b",
+            HashAlgorithm::Sha256 => "sha256"
+        }
+    }
+
+    /// Get the algorithm for the short name.
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "sha3" => Some(HashAlgorithm::Sha3),
+            "blake2b" => Some(HashAlgorithm::Blake2b),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None
+        }
+    }
+
+    /// Create a hash of this algorithm from raw bytes.
+    pub fn hash_from_bytes(&self, bytes: [u8; 32]) -> Hash {
+        match *self {
+            HashAlgorithm::Sha3 => Hash::Sha3(bytes),
+            HashAlgorithm::Blake2b => Hash::Blake2b(bytes),
+            HashAlgorithm::Sha256 => Hash::Sha256(bytes)
+        }
+    }
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        HashAlgorithm::Sha3
+    }
 }
 
 fn half_byte_to_string(byte: u8) -> String {
@@ -34,8 +113,8 @@ fn half_byte_to_string(byte: u8) -> String {
     }.to_string()
 }
 
-fn hex_str_to_u8(byte: u8) -> u8 {
-    match byte {
+fn hex_str_to_u8(byte: u8) -> Result<u8, HashParseError> {
+    Ok(match byte {
         0x30 => 0,
         0x31 => 1,
         0x32 => 2,
@@ -52,12 +131,19 @@ fn hex_str_to_u8(byte: u8) -> u8 {
         0x64 => 13,
         0x65 => 14,
         0x66 => 15,
-        _ => 0
-    }
+        0x41 => 10,
+        0x42 => 11,
+        0x43 => 12,
+        0x44 => 13,
+        0x45 => 14,
+        0x46 => 15,
+        _ => return Err(HashParseError::InvalidCharacter(byte as char))
+    })
 }
 
 
 
+
 fn byte_to_string(byte: u8) -> String {
     let mut res = String::new();
     res.push_str(&half_byte_to_string(byte / 16));
@@ -79,7 +165,21 @@ impl Hash {
     pub fn get_bytes(&self) -> Box<[u8]>{
         match self {
             &Hash::None => Box::new([0u8;0]),
-            &Hash::Sha3(x) => Box::new(x)
+            &Hash::Sha3(x) => Box::new(x),
+            &Hash::Blake2b(x) => Box::new(x),
+            &Hash::Sha256(x) => Box::new(x)
+        }
+    }
+
+    /// Returns the algorithm which generated the hash.
+    ///
+    /// Hash::None has no algorithm.
+    pub fn algorithm(&self) -> Option<HashAlgorithm> {
+        match *self {
+            Hash::None => None,
+            Hash::Sha3(_) => Some(HashAlgorithm::Sha3),
+            Hash::Blake2b(_) => Some(HashAlgorithm::Blake2b),
+            Hash::Sha256(_) => Some(HashAlgorithm::Sha256)
         }
     }
 
@@ -88,82 +188,230 @@ impl Hash {
         bytes_to_string(&*self.get_bytes())
     }
 
-    pub fn from_string(str: String) -> Hash {
+    /// Parse the hex string as sha3 hash.
+    ///
+    /// # Errors
+    /// Fails if the string doesn't consist of exactly 64 hex characters.
+    pub fn from_string(str: String) -> Result<Hash, HashParseError> {
+        Hash::from_string_with(str, HashAlgorithm::Sha3)
+    }
+
+    /// Parse the hex string as hash of the given algorithm.
+    ///
+    /// # Errors
+    /// Fails if the string doesn't consist of exactly 64 hex characters.
+    pub fn from_string_with(str: String, algorithm: HashAlgorithm)
+            -> Result<Hash, HashParseError> {
         let bytes = str.as_bytes();
+        if bytes.len() != 64 {
+            return Err(HashParseError::InvalidLength(bytes.len()));
+        }
         let mut res = [0u8; 32];
         for i in 0..32 {
-            let value: u8 = hex_str_to_u8(bytes[2 * i]) * 16 + hex_str_to_u8(bytes[2 * i + 1]);
-            res[i] = value;
+            let high = try!(hex_str_to_u8(bytes[2 * i]));
+            let low = try!(hex_str_to_u8(bytes[2 * i + 1]));
+            res[i] = high * 16 + low;
         }
-        Hash::Sha3(res)
+        Ok(algorithm.hash_from_bytes(res))
     }
 
     /// Returns a sha3-256 hash of the byte array.
     pub fn hash_bytes(bytes: &[u8]) -> Hash {
-        let mut sha3 = Sha3::sha3_256();
-        sha3.input(bytes);
-        let mut res = [0u8; 32];
-        sha3.result(&mut res);
-        Hash::Sha3(res)
+        HashAlgorithm::Sha3.hash_bytes(bytes)
     }
 
     /// Generate a new hash by compining this hash with another one.
+    ///
+    /// The algorithm of this hash is used.
     pub fn hash_with(&self, o: Hash) -> Hash {
         let mut vec: Vec<u8> = Vec::new();
         vec.extend_from_slice(&*self.get_bytes());
         vec.extend_from_slice(&*o.get_bytes());
-        Hash::hash_bytes(vec.as_slice())
+        self.algorithm().unwrap_or_default().hash_bytes(vec.as_slice())
+    }
+}
+
+/// Error when a hash could not be parsed from a string.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashParseError {
+    /// The hex part has the wrong number of characters.
+    InvalidLength(usize),
+
+    /// The string contains a character which is not a hex digit.
+    InvalidCharacter(char),
+
+    /// The algorithm prefix is unknown.
+    UnknownAlgorithm(String)
+}
+
+impl fmt::Display for HashParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HashParseError::InvalidLength(len) =>
+                write!(f, "Hash must have 64 hex characters but has {}", len),
+            HashParseError::InvalidCharacter(c) =>
+                write!(f, "Invalid character in hash: {:?}", c),
+            HashParseError::UnknownAlgorithm(ref name) =>
+                write!(f, "Unknown hash algorithm: {}", name)
+        }
+    }
+}
+
+impl Error for HashParseError {
+    fn description(&self) -> &str {
+        match *self {
+            HashParseError::InvalidLength(_) => "Invalid hash length",
+            HashParseError::InvalidCharacter(_) => "Invalid character in hash",
+            HashParseError::UnknownAlgorithm(_) => "Unknown hash algorithm"
+        }
+    }
+}
+
+/// Displays the hash as hex string.
+///
+/// Hashes which don't use sha3 are prefixed by the algorithm name like
+/// `blake2b:<hex>`.  The output can be parsed again by `str::parse`.
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.algorithm() {
+            None | Some(HashAlgorithm::Sha3) => write!(f, "{}", self.as_string()),
+            Some(algorithm) => write!(f, "{}:{}", algorithm.name(), self.as_string())
+        }
+    }
+}
+
+impl FromStr for Hash {
+    type Err = HashParseError;
+
+    fn from_str(s: &str) -> Result<Hash, HashParseError> {
+        match s.find(':') {
+            None => Hash::from_string(s.to_string()),
+            Some(pos) => {
+                let name = &s[..pos];
+                match HashAlgorithm::from_name(name) {
+                    Some(algorithm) => Hash::from_string_with(s[pos + 1..].to_string(), algorithm),
+                    None => Err(HashParseError::UnknownAlgorithm(name.to_string()))
+                }
+            }
+        }
     }
 }
 
 /// Can generate a hash type which represents the current type.
 pub trait Hashable {
     fn as_hash(&self) -> Hash;
+
+    /// Generate the hash with the given algorithm.
+    ///
+    /// By default, the algorithm is ignored and as_hash is used.
+    fn as_hash_with(&self, _: HashAlgorithm) -> Hash {
+        self.as_hash()
+    }
 }
 
 impl Hashable for Hash {
     fn as_hash(&self) -> Hash {
         Hash::hash_bytes(&*self.get_bytes())
     }
+
+    fn as_hash_with(&self, algorithm: HashAlgorithm) -> Hash {
+        algorithm.hash_bytes(&*self.get_bytes())
+    }
 }
 
-/// Implement Hashable for any Debug
+/// The hash of a value is not the one which was recorded before.
 ///
-/// This macro generates a valid Hashable implementation
-/// for any Debug type.  This can be handy since Debug can automatically
-/// implemented by the compiler.
+/// Returned by check_hash_stable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashDrift {
+    pub expected: Hash,
+    pub actual: Hash
+}
+
+impl fmt::Display for HashDrift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hash drift detected: expected {} but got {}.  Existing logs which \
+                   contain the type can't be verified anymore.",
+               self.expected, self.actual)
+    }
+}
+
+impl Error for HashDrift {
+    fn description(&self) -> &str {
+        "Hash drift detected"
+    }
+}
+
+/// Check if the value still has the recorded hash.
+///
+/// It's meant for tests which pin the hash of a sample value, so a change
+/// in the encoding of a type is noticed before it's released.
+pub fn check_hash_stable<T: Hashable>(value: &T, expected: Hash) -> Result<(), HashDrift> {
+    let actual = value.as_hash();
+    if actual != expected {
+        return Err(HashDrift {
+            expected: expected,
+            actual: actual
+        });
+    }
+    Ok(())
+}
+
+/// Fail if the hash of the value differs from the recorded hash
+///
+/// The expected hash is given as string like it's displayed.
 ///
 /// # Examples
 /// ```
 /// #[macro_use] extern crate tbd;
 ///
 /// use tbd::hash::*;
-///
-/// #[derive(Debug)]
-/// struct A {
-///    x: u8
-/// }
-/// hashable_for_debug!(A);
+/// use tbd::io::*;
 ///
 /// fn main() {
-///     let a = A {x: 42u8};
-///     // Can generate a hash now
-///     let hash: Hash = a.as_hash();
-///     assert_eq!("dbd0820fbce3804d3edc974e8e31cdee04172029528ea50b25db44356911fac1",
-///                   hash.as_string());
+///     assert_hash_stable!(42u8,
+///         "82283b4b030589a7aa0ca28b8e933ac0bd89738a0df509806c864366deec31d7");
 /// }
 /// ```
 #[macro_export]
-macro_rules! hashable_for_debug {
-    ($debug_type:path) => {
-        impl Hashable for $debug_type {
-            fn as_hash(&self) -> Hash {
-                let string_value = format!("{:?}", self);
-                Hash::hash_bytes(string_value.as_bytes())
-            }
+macro_rules! assert_hash_stable {
+    ($value:expr, $expected:expr) => {
+        if let Err(drift) = $crate::hash::check_hash_stable(
+                &$value, $expected.parse().expect("Invalid expected hash")) {
+            panic!("{}", drift);
         }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::super::hash::*;
+
+    #[test]
+    fn parse_test() {
+        let hash = Hash::hash_bytes(b"foo");
+        assert_eq!(Ok(hash), Hash::from_string(hash.as_string()));
+        assert_eq!(Ok(hash), hash.as_string().to_uppercase().parse());
+        assert_eq!(Ok(hash), format!("{}", hash).parse());
 
+        let blake = HashAlgorithm::Blake2b.hash_bytes(b"foo");
+        assert_eq!(format!("blake2b:{}", blake.as_string()), format!("{}", blake));
+        assert_eq!(Ok(blake), format!("{}", blake).parse());
 
+        assert_eq!(Err(HashParseError::InvalidLength(3)), "abc".parse::<Hash>());
+        let mut invalid = hash.as_string();
+        invalid.pop();
+        invalid.push('x');
+        assert_eq!(Err(HashParseError::InvalidCharacter('x')), invalid.parse::<Hash>());
+        assert_eq!(Err(HashParseError::UnknownAlgorithm("md5".to_string())),
+                   format!("md5:{}", hash.as_string()).parse::<Hash>());
+    }
+
+    #[test]
+    fn drift_test() {
+        let hash = Hash::hash_bytes(b"foo");
+        assert_eq!(Ok(()), check_hash_stable(&hash, hash.as_hash()));
+        assert_eq!(Err(HashDrift { expected: hash, actual: hash.as_hash() }),
+                   check_hash_stable(&hash, hash));
+    }
+}
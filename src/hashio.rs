@@ -10,20 +10,29 @@
 //! If a trait implements Writable, Readable and Hashable,
 //! it can also implement the HashIO trait which allows
 //! the values to be cashed.
+//!
+//! HashIO used to write files itself and had a public `base_path`
+//! field.  The files are handled by an ObjectStore now, so the path of
+//! a HashIO created by `new` or `open` is available by `base_path()`
+//! and the filenames of objects by `DirectoryStore`.
 
 extern crate crypto;
 extern crate byteorder;
+extern crate time;
 
 
+use std::cell::RefCell;
 use std::io::{Read, Write};
+use std::rc::Rc;
 use std::{io, error, fmt};
+use cache::*;
 use hash::*;
 use io::*;
-use std::fs::{File, create_dir_all};
+use pack::*;
+use store::*;
 use std::collections::BTreeMap;
 use std::vec::Vec;
-use std::path::Path;
-use std::fs::rename;
+use self::time::Tm;
 
 
 
@@ -31,7 +40,13 @@ use std::fs::rename;
 pub enum HashIOError {
     Undefined(String),
     IOError(io::Error),
-    ParseError(Box<error::Error>)
+    ParseError(Box<error::Error>),
+
+    /// The stored data has a version which can't be read.
+    UnsupportedVersion(u32),
+
+    /// More than one object starts with the given hash prefix.
+    AmbiguousPrefix(String, Vec<Hash>)
 }
 
 
@@ -41,7 +56,11 @@ impl fmt::Display for HashIOError {
         match *self {
             HashIOError::Undefined(ref msg) => write!(f, "Undefined error: {}", msg),
             HashIOError::IOError(ref err) => err.fmt(f),
-            HashIOError::ParseError(ref err) => write!(f, "Parse error: {}", err)
+            HashIOError::ParseError(ref err) => write!(f, "Parse error: {}", err),
+            HashIOError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported version: {}", version),
+            HashIOError::AmbiguousPrefix(ref prefix, ref hashes) =>
+                write!(f, "Hash prefix {} is ambiguous, {} objects match", prefix, hashes.len())
         }
     }
 }
@@ -51,7 +70,19 @@ impl error::Error for HashIOError {
         match *self {
             HashIOError::Undefined(ref msg) => msg,
             HashIOError::IOError(ref err) => err.description(),
-            HashIOError::ParseError(ref err) => err.description()
+            HashIOError::ParseError(ref err) => err.description(),
+            HashIOError::UnsupportedVersion(_) => "Unsupported version",
+            HashIOError::AmbiguousPrefix(_, _) => "Ambiguous hash prefix"
+        }
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            HashIOError::Undefined(_) => None,
+            HashIOError::IOError(ref err) => Some(err),
+            HashIOError::ParseError(ref err) => Some(&**err),
+            HashIOError::UnsupportedVersion(_) => None,
+            HashIOError::AmbiguousPrefix(_, _) => None
         }
     }
 }
@@ -62,8 +93,18 @@ impl From<io::Error> for HashIOError {
     }
 }
 
+/// Reads and writes hashable values from and to an ObjectStore.
+///
+/// New objects are hashed with the configured HashAlgorithm.  Objects
+/// are always read by the hash they were stored with, so a store can
+/// contain objects of several algorithms.
+///
+/// Read objects can be kept in an ObjectCache which is disabled by default.
 pub struct HashIO {
-    pub base_path: String
+    base_path: Option<String>,
+    store: Box<ObjectStore>,
+    algorithm: HashAlgorithm,
+    cache: RefCell<ObjectCache>
 }
 
 pub trait HashIOImpl<T: Hashable> {
@@ -78,63 +119,175 @@ pub trait HashIOImpl<T: Hashable> {
 }
 
 impl HashIO {
+    /// Create a HashIO which stores its objects in the given directory.
+    ///
+    /// Objects are stored as loose files, use open for a directory which
+    /// might contain a pack.
     pub fn new(path: String) -> HashIO {
+        let mut hashio = HashIO::with_store(Box::new(DirectoryStore::new(path.clone())));
+        hashio.base_path = Some(path);
+        hashio
+    }
+
+    /// Create a HashIO for the directory which uses its pack if it has one.
+    ///
+    /// # Errors
+    /// Fails if the pack exists but can't be opened.
+    pub fn open(path: String) -> Result<HashIO, HashIOError> {
+        let mut hashio = HashIO::with_store(try!(open_store(path.clone())));
+        hashio.base_path = Some(path);
+        Ok(hashio)
+    }
+
+    /// Create a HashIO on top of any ObjectStore.
+    pub fn with_store(store: Box<ObjectStore>) -> HashIO {
         HashIO {
-            base_path: path
+            base_path: None,
+            store: store,
+            algorithm: HashAlgorithm::default(),
+            cache: RefCell::new(ObjectCache::disabled())
         }
     }
 
+    /// Directory of the objects if the HashIO was created by path.
+    pub fn base_path(&self) -> Option<&str> {
+        self.base_path.as_ref().map(|path| &path[..])
+    }
+
+    /// Directory of the loose object file of the hash.
+    ///
+    /// # Panics
+    /// Panics if the HashIO was not created by path.
+    #[deprecated(note = "use DirectoryStore::directory_for_hash")]
     pub fn directory_for_hash(&self, hash: &Hash) -> String {
-        let hash_str = hash.as_string();
-        let mut result = String::new();
-        result.push_str(&self.base_path);
-        result.push('/');
-        result.push_str(&hash_str[0..2]);
-        result.push('/');
-        result
+        self.directory_store().directory_for_hash(hash)
     }
 
+    /// Filename of the loose object file of the hash.
+    ///
+    /// # Panics
+    /// Panics if the HashIO was not created by path.
+    #[deprecated(note = "use DirectoryStore::filename_for_hash")]
     pub fn filename_for_hash(&self, hash: &Hash) -> String {
-        let hash_str = hash.as_string();
-        let mut result = self.directory_for_hash(hash);
-        result.push_str(&hash_str[2..]);
-        result
+        self.directory_store().filename_for_hash(hash)
+    }
+
+    fn directory_store(&self) -> DirectoryStore {
+        DirectoryStore::new(self.base_path.clone()
+            .expect("Only a HashIO which was created by path has object files"))
+    }
+
+    /// Use the given algorithm to hash new objects.
+    pub fn with_algorithm(mut self, algorithm: HashAlgorithm) -> HashIO {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Algorithm which is used to hash new objects.
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Keep up to max_entries read objects with max_bytes in memory.
+    pub fn with_cache(mut self, max_entries: usize, max_bytes: usize) -> HashIO {
+        self.cache = RefCell::new(ObjectCache::new(max_entries, max_bytes));
+        self
+    }
+
+    /// Hit and miss counters of the object cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.borrow().stats()
+    }
+
+    /// Drop all objects from the cache.
+    ///
+    /// Required if objects were deleted from the store directly.
+    pub fn clear_cache(&self) {
+        self.cache.borrow_mut().clear()
+    }
+
+    /// Access the underlying store.
+    pub fn store(&self) -> &ObjectStore {
+        &*self.store
     }
 
     pub fn get<T>(&self, hash: &Hash) -> Result<T, HashIOError>
                 where HashIO: HashIOImpl<T>,
                       T: Hashable {
-        let filename = self.filename_for_hash(hash);
-        let mut read = try!(File::open(filename));
+        let bytes = try!(self.get_bytes(hash));
+        let mut read: &[u8] = &bytes;
         let result : T = try!(self.receive_hashable(&mut read));
         Ok(result)
     }
 
+    /// Find the full hash of a stored object by the start of its hex string.
+    ///
+    /// Like in git, an abbreviated hash can be used as long as it's unique.
+    /// The prefix must contain at least the two characters of the directory
+    /// name.
+    ///
+    /// # Errors
+    /// Fails if the prefix is invalid, if no object matches or with
+    /// AmbiguousPrefix if more than one object matches.
+    pub fn resolve_prefix(&self, prefix: &str) -> Result<Hash, HashIOError> {
+        let prefix = prefix.to_lowercase();
+        if prefix.len() < 2 || prefix.len() > 64 {
+            return Err(HashIOError::Undefined(
+                format!("Hash prefix must have 2 to 64 characters: {}", prefix)));
+        }
+        if let Some(c) = prefix.chars().find(|c| !c.is_digit(16)) {
+            return Err(HashIOError::ParseError(Box::new(HashParseError::InvalidCharacter(c))));
+        }
+        let mut hashes = try!(self.store.find_prefix(&prefix));
+        match hashes.len() {
+            0 => Err(HashIOError::IOError(io::Error::new(io::ErrorKind::NotFound,
+                        format!("No object found for hash prefix: {}", prefix)))),
+            1 => Ok(hashes.remove(0)),
+            _ => Err(HashIOError::AmbiguousPrefix(prefix, hashes))
+        }
+    }
+
+    /// Hash of the raw bytes stored for the given hash.
+    ///
+    /// The bytes are hashed with the algorithm of the given hash, so the
+    /// result differs from it if the stored object was changed.  This
+    /// also works for objects which are migrated when they are read.
+    pub fn stored_hash(&self, hash: &Hash) -> Result<Hash, HashIOError> {
+        let bytes = try!(self.get_bytes(hash));
+        Ok(hash.algorithm().unwrap_or_default().hash_bytes(&bytes))
+    }
+
+    /// Read the raw bytes of the object from the cache or the store.
+    fn get_bytes(&self, hash: &Hash) -> Result<Rc<Vec<u8>>, HashIOError> {
+        if *hash == Hash::None {
+            return Err(HashIOError::Undefined("Cannot get an object for an empty hash".to_string()));
+        }
+        let mut cache = self.cache.borrow_mut();
+        if !cache.is_enabled() {
+            return Ok(Rc::new(try!(self.store.get(hash))));
+        }
+        if let Some(bytes) = cache.get(hash) {
+            return Ok(bytes);
+        }
+        let bytes = try!(self.store.get(hash));
+        Ok(cache.insert(*hash, bytes))
+    }
+
     pub fn put<T>(&self, hashable: &T) -> Result<(), HashIOError>
                 where HashIO: HashIOImpl<T>,
                       T: Hashable {
-        let hash = hashable.as_hash();
+        let hash = hashable.as_hash_with(self.algorithm);
 
         // First, if the entry already exists, skip the insert because it's already saved.
-        let filename = self.filename_for_hash(&hash);
-        if !Path::new(&filename).exists() {
+        if !self.store.exists(&hash) {
             // First store all childs and their childs.
             // So we make sure that all dependencies are available when the current object has
             // finished writing.
             try!(self.store_childs(hashable));
 
-            // First write in a slightly modified file which will be renamed when writing was
-            // finished.  So we only have valid files or nothing on the expected position but
-            // nothing unfinished.
-            let safe_filename = format!("{}_", filename);
-            let dir = self.directory_for_hash(&hash);
-            try!(create_dir_all(dir));
-            {
-                let mut write = try!(File::create(Path::new(&safe_filename)));
-                try!(self.store_hashable(hashable, &mut write));
-                // 'write' will go out of scope now and so the file handle will be closed
-            }
-            try!(rename(safe_filename, filename));
+            let mut bytes: Vec<u8> = Vec::new();
+            try!(self.store_hashable(hashable, &mut bytes));
+            try!(self.store.put(&hash, &bytes));
         }
         Ok(())
     }
@@ -142,6 +295,39 @@ impl HashIO {
 
 
 
+impl ChildReader for HashIO {
+    fn read_child<T: Readable>(&self, hash: &Hash) -> Result<T, HashIOError> {
+        let bytes = try!(self.get_bytes(hash));
+        let mut read: &[u8] = &bytes;
+        T::read_from(&mut read, self)
+    }
+}
+
+
+/// Implement HashIOImpl for any Writable and Readable
+///
+/// The value is written by Writable and read by Readable.  The type
+/// must not have any childs since store_childs does nothing.
+macro_rules! hashio_for_readable {
+    ($readable_type:ty) => {
+        impl HashIOImpl<$readable_type> for HashIO {
+            fn receive_hashable<R>(&self, read: &mut R) -> Result<$readable_type, HashIOError>
+                    where R: Read {
+                <$readable_type as Readable>::read_from(read, self)
+            }
+
+            fn store_hashable<W>(&self, hashable: &$readable_type, write: &mut W)
+                    -> Result<(), HashIOError>
+                    where W: Write {
+                try!(hashable.write_to_with(write, self.algorithm()));
+                Ok(())
+            }
+        }
+    }
+}
+
+
+
 impl Writable for String {
     fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
         let str_bytes = self.as_bytes();
@@ -154,31 +340,81 @@ impl Writable for String {
 }
 hashable_for_writable!(String);
 
-impl HashIOImpl<String> for HashIO {
-    fn store_hashable<W>(&self, hashable: &String, write: &mut W) -> Result<(), HashIOError>
-                    where W: Write {
-        try!(hashable.write_to(write));
-        Ok(())
-    }
-
-    fn receive_hashable<R>(&self, read: &mut R) -> Result<String, HashIOError>
-                    where R: Read {
+impl Readable for String {
+    fn read_from<R: Read, C: ChildReader>(read: &mut R, _: &C) -> Result<String, HashIOError> {
         let len = try!(read_u32(read));
         let bytes = try!(read_bytes(read, len as usize));
         let res = try!(String::from_utf8(bytes).map_err(|x| HashIOError::ParseError(Box::new(x))));
         Ok(res)
     }
+
+    fn placeholder() -> Option<String> {
+        Some(String::new())
+    }
 }
 
+hashio_for_readable!(String);
+hashio_for_readable!(u8);
+hashio_for_readable!(u32);
+hashio_for_readable!(u64);
+hashio_for_readable!(i16);
+hashio_for_readable!(i32);
+hashio_for_readable!(f32);
+hashio_for_readable!(Tm);
+
+
 
 
 
 
+/// Migration for types which can't read older versions.
+///
+/// It's used by tbd_model! if no migration function is given.
+pub fn no_migration<T, R, C>(version: u32, _: &mut R, _: &C) -> Result<T, HashIOError>
+        where R: Read, C: ChildReader {
+    Err(HashIOError::UnsupportedVersion(version))
+}
+
 
+/// Generate a model type with Writable, Readable and HashIOImpl.
+///
+/// The first list contains attributes which are stored inline by the given
+/// write and read functions, the second list contains childs which are
+/// stored by their hash.
+///
+/// Each model writes a version number in front of its data.  It's 0 by
+/// default and can be set with `version <number>`.  If the type changes,
+/// increase the version and provide a migration function with
+/// `migrate <function>`.  It's called with the stored version for all
+/// older versions and must read the rest of the old data:
+///
+/// ```text
+/// fn migrate_task<R, C>(version: u32, read: &mut R, childs: &C)
+///         -> Result<Task, HashIOError>
+///         where R: Read, C: ChildReader { ... }
+///
+/// tbd_model!(Task, version 1, migrate migrate_task, [ ... ], [ ... ]);
+/// ```
 macro_rules! tbd_model {
     ($model_name:ident,
             [ $( [$attr_name:ident : $attr_type:ty, $exp_fn:ident, $imp_fn:ident ] ),* ] ,
             [ $( [$hash_name:ident : $hash_type:ty] ),* ]) => {
+        tbd_model!($model_name, version 0, migrate no_migration,
+                   [ $( [$attr_name : $attr_type, $exp_fn, $imp_fn] ),* ],
+                   [ $( [$hash_name : $hash_type] ),* ]);
+    };
+
+    ($model_name:ident, version $version:expr,
+            [ $( [$attr_name:ident : $attr_type:ty, $exp_fn:ident, $imp_fn:ident ] ),* ] ,
+            [ $( [$hash_name:ident : $hash_type:ty] ),* ]) => {
+        tbd_model!($model_name, version $version, migrate no_migration,
+                   [ $( [$attr_name : $attr_type, $exp_fn, $imp_fn] ),* ],
+                   [ $( [$hash_name : $hash_type] ),* ]);
+    };
+
+    ($model_name:ident, version $version:expr, migrate $migrate:path,
+            [ $( [$attr_name:ident : $attr_type:ty, $exp_fn:ident, $imp_fn:ident ] ),* ] ,
+            [ $( [$hash_name:ident : $hash_type:ty] ),* ]) => {
 
         #[derive(Debug, Clone, PartialEq)]
         pub struct $model_name {
@@ -188,11 +424,16 @@ macro_rules! tbd_model {
 
         impl Writable for $model_name {
             fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
+                self.write_to_with(write, HashAlgorithm::Sha3)
+            }
+
+            fn write_to_with<W: Write>(&self, write: &mut W, algorithm: HashAlgorithm)
+                    -> Result<usize, io::Error> {
                 let mut size = 0;
-                try!(write_u32(0, write));
+                try!(write_u32($version, write));
                 size += $( try!($exp_fn(self.$attr_name, write)); )*
                 $(
-                    try!(write_hash(&self.$hash_name.as_hash(), write));
+                    try!(write_hash(&self.$hash_name.as_hash_with(algorithm), write));
                     size += 32;
                 )*
                 Ok(size)
@@ -201,16 +442,28 @@ macro_rules! tbd_model {
 
         hashable_for_writable!($model_name);
 
-        impl HashIOImpl<$model_name> for HashIO {
-            fn receive_hashable<R>(&self, read: &mut R) -> Result<$model_name, HashIOError>
-                    where R: Read {
-                try!(read_u32(read));
+        impl Versioned for $model_name {
+            fn version() -> u32 {
+                $version
+            }
+        }
+
+        impl Readable for $model_name {
+            fn read_from<R: Read, C: ChildReader>(read: &mut R, childs: &C)
+                    -> Result<$model_name, HashIOError> {
+                let version = try!(read_u32(read));
+                if version != $version {
+                    if version > $version {
+                        return Err(HashIOError::UnsupportedVersion(version));
+                    }
+                    return $migrate(version, read, childs);
+                }
                 $( let $attr_name = try!($imp_fn(read)); )* ;
                 $(
                     let $hash_name;
                     {
                         let hash_val = try!(read_hash(read));
-                        $hash_name = try!(self.get(&hash_val));
+                        $hash_name = try!(childs.read_child(&hash_val));
                     }
                 )*
                 Ok($model_name{
@@ -219,6 +472,28 @@ macro_rules! tbd_model {
                     })
             }
 
+            fn placeholder() -> Option<$model_name> {
+                $( let $attr_name = match <$attr_type as Readable>::placeholder() {
+                    Some(value) => value,
+                    None => return None
+                }; )*
+                $( let $hash_name = match <$hash_type as Readable>::placeholder() {
+                    Some(value) => value,
+                    None => return None
+                }; )*
+                Some($model_name{
+                    $($attr_name: $attr_name,)*
+                    $($hash_name: $hash_name),*
+                    })
+            }
+        }
+
+        impl HashIOImpl<$model_name> for HashIO {
+            fn receive_hashable<R>(&self, read: &mut R) -> Result<$model_name, HashIOError>
+                    where R: Read {
+                <$model_name as Readable>::read_from(read, self)
+            }
+
             fn store_childs(&self, hashable: &$model_name) -> Result<(), HashIOError> {
                 $( try!(self.put(&hashable.$hash_name)); )*
                 Ok(())
@@ -226,7 +501,7 @@ macro_rules! tbd_model {
 
             fn store_hashable<W>(&self, hashable: &$model_name, write: &mut W) -> Result<(), HashIOError>
                     where W: Write {
-                try!(hashable.write_to(write));
+                try!(hashable.write_to_with(write, self.algorithm()));
                 Ok(())
             }
         }
@@ -240,6 +515,7 @@ mod test {
     use super::super::hash::*;
     use super::super::hashio::*;
     use super::super::io::*;
+    use super::super::store::*;
     use std::io::{Read, Write};
 
     #[derive(Debug)]
@@ -248,7 +524,14 @@ mod test {
         b: String
     }
 
-    hashable_for_debug!(A);
+    impl Writable for A {
+        fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
+            let mut size = try!(write_u8(self.a, write));
+            size += try!(write_hash(&self.b.as_hash(), write));
+            Ok(size)
+        }
+    }
+    hashable_for_writable!(A);
 
     impl HashIOImpl<A> for HashIO {
         fn receive_hashable<R>(&self, read: &mut R) -> Result<A, HashIOError>
@@ -266,14 +549,14 @@ mod test {
         fn store_hashable<W>(&self, hashable: &A, write: &mut W) -> Result<(), HashIOError>
                     where W: Write {
             try!(write_u8(hashable.a, write));
-            try!(write_hash(&hashable.b.as_hash(), write));
+            try!(write_hash(&hashable.b.as_hash_with(self.algorithm()), write));
             Ok(())
         }
     }
 
     #[test]
     fn simple_test() {
-        let hash_io = HashIO::new("savetest".to_string());
+        let hash_io = HashIO::with_store(Box::new(MemoryStore::new()));
         let a_hash;
         {
             let a = A {
@@ -288,6 +571,19 @@ mod test {
         assert_eq!(10, a2.a);
         assert_eq!("Test".to_string(), a2.b);
     }
+
+    #[test]
+    fn truncated_test() {
+        let store = MemoryStore::new();
+        let hash_io = HashIO::with_store(Box::new(store.clone()));
+        let hash = "Test".to_string().as_hash();
+        // Length says 4 bytes but only 2 are stored
+        store.put(&hash, &[0, 0, 0, 4, b'T', b'e']).unwrap();
+        match hash_io.get::<String>(&hash) {
+            Err(HashIOError::IOError(ref err)) => assert_eq!(true, is_truncated(err)),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
 }
 
 
@@ -297,6 +593,7 @@ mod test2 {
     use super::super::hash::*;
     use super::super::hashio::*;
     use super::super::io::*;
+    use super::super::store::*;
     use std::io::{Read, Write};
     use std::io;
 
@@ -316,7 +613,7 @@ mod test2 {
 
     #[test]
     fn simple_test() {
-        let hash_io = HashIO::new("savetest".to_string());
+        let hash_io = HashIO::with_store(Box::new(MemoryStore::new()));
         let my_hash;
         let b = B {
             foo: "Foo".to_string(),
@@ -337,18 +634,50 @@ mod test2 {
         assert_eq!(b.foo, b_read.foo);
         assert_eq!(b.foobar, b_read.foobar);
     }
+
+    #[test]
+    fn stable_hash_test() {
+        use std::collections::BTreeMap;
+
+        let mut map = BTreeMap::new();
+        map.insert("one".to_string(), 1u32);
+        // Changing one of these hashes breaks all existing logs
+        assert_hash_stable!("Foo".to_string(),
+            "8c51e5401ff048ed6bbdea165a053192e808686dcf7a560f81ed51e2fd3277fb");
+        assert_hash_stable!(1u32,
+            "7250d6eb424a2ae67b384738f4c918ac6bacb340e0ab775c1076ebbefb8eeccc");
+        assert_hash_stable!(-1i32,
+            "904200c7d454fe3e8e1dfaa21b4e667f250cabd8f5730bd361feacf77fab1686");
+        assert_hash_stable!(vec!["Foo".to_string()],
+            "34ad60e3ef1f16b30f5a1799d0d4281a0240255710758e86845d0039dcbde301");
+        assert_hash_stable!(map,
+            "ad8a7efdc6fadb38e5312f494777d6123a44381757869e8912fe5ad67e641907");
+        assert_hash_stable!(A{a: 20, b: "Foo".to_string()},
+            "780645ccc48902beaf419fbd7f7bb5260b01a7ab97e766afd49855aa625ee857");
+    }
 }
 
 
+impl<T, U> Versioned for BTreeMap<T, U> {
+    fn version() -> u32 {
+        0
+    }
+}
+
 impl<T, U> Writable for BTreeMap<T, U>
     where T: Writable, U: Writable, T: Hashable, U: Hashable {
     fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
-        try!(write_u32(0, write));
+        self.write_to_with(write, HashAlgorithm::Sha3)
+    }
+
+    fn write_to_with<W: Write>(&self, write: &mut W, algorithm: HashAlgorithm)
+            -> Result<usize, io::Error> {
+        try!(write_u32(<BTreeMap<T, U> as Versioned>::version(), write));
         try!(write_u32(self.len() as u32, write));
         let mut size: usize = 0;
         for (key, value) in self {
-            size += try!(write_hash(&key.as_hash(), write));
-            size += try!(write_hash(&value.as_hash(), write));
+            size += try!(write_hash(&key.as_hash_with(algorithm), write));
+            size += try!(write_hash(&value.as_hash_with(algorithm), write));
         }
         Ok(size)
     }
@@ -359,12 +688,42 @@ impl<T, U> Hashable for BTreeMap<T, U>
     fn as_hash(&self) -> Hash {
         self.writable_to_hash()
     }
+
+    fn as_hash_with(&self, algorithm: HashAlgorithm) -> Hash {
+        self.writable_to_hash_with(algorithm)
+    }
+}
+
+impl<T, U> Readable for BTreeMap<T, U>
+    where T: Readable, U: Readable, T: Ord {
+    fn read_from<R: Read, C: ChildReader>(read: &mut R, childs: &C)
+            -> Result<BTreeMap<T, U>, HashIOError> {
+        let mut res = BTreeMap::<T, U>::new();
+        let version = try!(read_u32(read));
+        if version != <BTreeMap<T, U> as Versioned>::version() {
+            return Err(HashIOError::UnsupportedVersion(version));
+        }
+        let entries = try!(read_u32(read));
+        for _ in 0..entries {
+            let key_hash = try!(read_hash(read));
+            let value_hash = try!(read_hash(read));
+            let key = try!(childs.read_child(&key_hash));
+            let value = try!(childs.read_child(&value_hash));
+            res.insert(key, value);
+        }
+        Ok(res)
+    }
+
+    fn placeholder() -> Option<BTreeMap<T, U>> {
+        Some(BTreeMap::new())
+    }
 }
 
 impl<T, U> HashIOImpl<BTreeMap<T, U>> for HashIO
     where HashIO: HashIOImpl<T>,
           HashIO: HashIOImpl<U>,
           T: Writable, U: Writable,
+          T: Readable, U: Readable,
           T: Hashable, U: Hashable,
           T: Ord {
     fn store_hashable<W>(&self, hashable: &BTreeMap<T, U>, write: &mut W) -> Result<(), HashIOError>
@@ -373,23 +732,13 @@ impl<T, U> HashIOImpl<BTreeMap<T, U>> for HashIO
             try!(self.put(key));
             try!(self.put(value));
         }
-        try!(hashable.write_to(write));
+        try!(hashable.write_to_with(write, self.algorithm()));
         Ok(())
     }
 
     fn receive_hashable<R>(&self, read: &mut R) -> Result<BTreeMap<T, U>, HashIOError>
         where R: Read {
-        let mut res = BTreeMap::<T, U>::new();
-        try!(read_u32(read));
-        let entries = try!(read_u32(read));
-        for _ in 0..entries {
-            let key_hash = try!(read_hash(read));
-            let value_hash = try!(read_hash(read));
-            let key = try!(self.get(&key_hash));
-            let value = try!(self.get(&value_hash));
-            res.insert(key, value);
-        }
-        Ok(res)
+        <BTreeMap<T, U> as Readable>::read_from(read, self)
     }
 }
 
@@ -398,6 +747,7 @@ mod btreemaptest {
     use super::super::hash::*;
     use super::super::hashio::*;
     use super::super::io::*;
+    use super::super::store::*;
     use std::io::{Read, Write};
     use std::io;
     use std::collections::BTreeMap;
@@ -408,7 +758,7 @@ mod btreemaptest {
 
     #[test]
     fn test() {
-        let hash_io = HashIO::new("savetest/btreemaptest".to_string());
+        let hash_io = HashIO::with_store(Box::new(MemoryStore::new()));
         let mut a = A { a: BTreeMap::new() };
         a.a.insert("one".to_string(), "1".to_string());
         a.a.insert("two".to_string(), "2".to_string());
@@ -419,14 +769,25 @@ mod btreemaptest {
     }
 }
 
+impl<T> Versioned for Vec<T> {
+    fn version() -> u32 {
+        0
+    }
+}
+
 impl<T> Writable for Vec<T>
     where T: Writable, T: Hashable {
     fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
-        try!(write_u32(0, write));
+        self.write_to_with(write, HashAlgorithm::Sha3)
+    }
+
+    fn write_to_with<W: Write>(&self, write: &mut W, algorithm: HashAlgorithm)
+            -> Result<usize, io::Error> {
+        try!(write_u32(<Vec<T> as Versioned>::version(), write));
         try!(write_u32(self.len() as u32, write));
         let mut size: usize = 0;
         for value in self {
-            size += try!(write_hash(&value.as_hash(), write));
+            size += try!(write_hash(&value.as_hash_with(algorithm), write));
         }
         Ok(size)
     }
@@ -437,30 +798,49 @@ impl<T> Hashable for Vec<T>
     fn as_hash(&self) -> Hash {
         self.writable_to_hash()
     }
+
+    fn as_hash_with(&self, algorithm: HashAlgorithm) -> Hash {
+        self.writable_to_hash_with(algorithm)
+    }
+}
+
+impl<T> Readable for Vec<T>
+    where T: Readable {
+    fn read_from<R: Read, C: ChildReader>(read: &mut R, childs: &C)
+            -> Result<Vec<T>, HashIOError> {
+        let mut res = Vec::<T>::new();
+        let version = try!(read_u32(read));
+        if version != <Vec<T> as Versioned>::version() {
+            return Err(HashIOError::UnsupportedVersion(version));
+        }
+        let entries = try!(read_u32(read));
+        for _ in 0..entries {
+            let value_hash = try!(read_hash(read));
+            let value = try!(childs.read_child(&value_hash));
+            res.push(value);
+        }
+        Ok(res)
+    }
+
+    fn placeholder() -> Option<Vec<T>> {
+        Some(Vec::new())
+    }
 }
 
 impl<T> HashIOImpl<Vec<T>> for HashIO
     where HashIO: HashIOImpl<T>,
-          T: Writable, T: Hashable {
+          T: Writable, T: Readable, T: Hashable {
     fn store_hashable<W>(&self, hashable: &Vec<T>, write: &mut W) -> Result<(), HashIOError>
         where W: Write {
         for value in hashable {
             try!(self.put(value));
         }
-        try!(hashable.write_to(write));
+        try!(hashable.write_to_with(write, self.algorithm()));
         Ok(())
     }
 
     fn receive_hashable<R>(&self, read: &mut R) -> Result<Vec<T>, HashIOError>
         where R: Read {
-        let mut res = Vec::<T>::new();
-        try!(read_u32(read));
-        let entries = try!(read_u32(read));
-        for _ in 0..entries {
-            let value_hash = try!(read_hash(read));
-            let value = try!(self.get(&value_hash));
-            res.push(value);
-        }
-        Ok(res)
+        <Vec<T> as Readable>::read_from(read, self)
     }
 }
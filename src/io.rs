@@ -3,14 +3,70 @@ extern crate byteorder;
 extern crate time;
 
 use std::io::{Read, Write};
-use self::crypto::sha3::Sha3;
-use self::crypto::digest::Digest;
 use self::byteorder::{BigEndian, ByteOrder};
 use std::io;
+use std::{error, fmt};
 
 use hash::*;
+use hashio::HashIOError;
 use self::time::*;
 
+
+/// Less data was available than a reader expected.
+///
+/// It's returned as io::Error with the kind UnexpectedEof by all read
+/// functions of this module.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TruncatedData {
+    /// Number of bytes which should be read.
+    pub expected: usize,
+
+    /// Number of bytes which were available.
+    pub actual: usize
+}
+
+impl fmt::Display for TruncatedData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Truncated data: expected {} bytes but got {}", self.expected, self.actual)
+    }
+}
+
+impl error::Error for TruncatedData {
+    fn description(&self) -> &str {
+        "Truncated data"
+    }
+}
+
+/// Check if the error was caused by truncated data.
+pub fn is_truncated(err: &io::Error) -> bool {
+    match err.get_ref() {
+        Some(inner) => inner.is::<TruncatedData>(),
+        None => false
+    }
+}
+
+fn truncated_error(expected: usize, actual: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, TruncatedData {
+        expected: expected,
+        actual: actual
+    })
+}
+
+/// Fill the whole buffer or fail with TruncatedData.
+pub fn read_exactly<R: ?Sized>(read: &mut R, buffer: &mut [u8]) -> Result<(), io::Error>
+        where R: Read {
+    let mut pos = 0;
+    while pos < buffer.len() {
+        match read.read(&mut buffer[pos..]) {
+            Ok(0) => return Err(truncated_error(buffer.len(), pos)),
+            Ok(n) => pos += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
 pub fn write_u8<W>(i: u8, write: &mut W) -> Result<usize, io::Error> where W: Write {
     let mut bytes = [0u8; 1];
     bytes[0] = i;
@@ -19,7 +75,7 @@ pub fn write_u8<W>(i: u8, write: &mut W) -> Result<usize, io::Error> where W: Wr
 
 pub fn read_u8<R>(read: &mut R) -> Result<u8, io::Error> where R: Read {
     let mut bytes = [0u8; 1];
-    try!(read.read(&mut bytes));
+    try!(read_exactly(read, &mut bytes));
     Ok(bytes[0])
 }
 
@@ -33,10 +89,22 @@ pub fn write_u32<W>(i: u32, write: &mut W) -> Result<usize, io::Error> where W:
 
 pub fn read_u32<R>(read: &mut R) -> Result<u32, io::Error> where R: Read {
     let mut bytes = [0u8; 4];
-    try!(read.read(&mut bytes));
+    try!(read_exactly(read, &mut bytes));
     Ok(BigEndian::read_u32(&bytes))
 }
 
+pub fn write_u64<W>(i: u64, write: &mut W) -> Result<usize, io::Error> where W: Write {
+    let mut bytes = [0u8; 8];
+    BigEndian::write_u64(&mut bytes, i);
+    write.write(&bytes)
+}
+
+pub fn read_u64<R>(read: &mut R) -> Result<u64, io::Error> where R: Read {
+    let mut bytes = [0u8; 8];
+    try!(read_exactly(read, &mut bytes));
+    Ok(BigEndian::read_u64(&bytes))
+}
+
 pub fn write_i32<W>(i: i32, write: &mut W) -> Result<usize, io::Error> where W: Write{
     let mut bytes = [0u8; 4];
     BigEndian::write_i32(&mut bytes, i);
@@ -45,7 +113,7 @@ pub fn write_i32<W>(i: i32, write: &mut W) -> Result<usize, io::Error> where W:
 
 pub fn read_i32<R>(read: &mut R) -> Result<i32, io::Error> where R: Read {
     let mut bytes = [0u8; 4];
-    try!(read.read(&mut bytes));
+    try!(read_exactly(read, &mut bytes));
     Ok(BigEndian::read_i32(&bytes))
 }
 
@@ -57,7 +125,7 @@ pub fn write_i16<W>(i: i16, write: &mut W) -> Result<usize, io::Error> where W:
 
 pub fn read_i16<R>(read: &mut R) -> Result<i16, io::Error> where R: Read {
     let mut bytes = [0u8; 2];
-    try!(read.read(&mut bytes));
+    try!(read_exactly(read, &mut bytes));
     Ok(BigEndian::read_i16(&bytes))
 }
 
@@ -69,7 +137,7 @@ pub fn write_f32<W>(f: f32, write: &mut W) -> Result<usize, io::Error> where W:
 
 pub fn read_f32<R>(read: &mut R) -> Result<f32, io::Error> where R: Read {
     let mut bytes = [0u8; 4];
-    try!(read.read(&mut bytes));
+    try!(read_exactly(read, &mut bytes));
     Ok(BigEndian::read_f32(&bytes))
 }
 
@@ -80,17 +148,24 @@ pub fn read_f32<R>(read: &mut R) -> Result<f32, io::Error> where R: Read {
 /// a sha3 representation of its output.
 pub trait Writable {
     fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error>;
+
+    /// Write itself and refer to childs by hashes of the given algorithm.
+    ///
+    /// Types with childs must implement it, by default write_to is used.
+    fn write_to_with<W: Write>(&self, write: &mut W, _: HashAlgorithm)
+            -> Result<usize, io::Error> {
+        self.write_to(write)
+    }
+
     fn writable_to_hash(&self) -> Hash {
+        self.writable_to_hash_with(HashAlgorithm::Sha3)
+    }
+
+    fn writable_to_hash_with(&self, algorithm: HashAlgorithm) -> Hash {
         let mut write: Vec<u8> = Vec::new();
-        self.write_to(&mut write)
+        self.write_to_with(&mut write, algorithm)
             .expect("Writing to a vec should not cause any issues");
-        let data = write.as_slice();
-        let mut hasher = Sha3::sha3_256();
-        let mut hash_bytes = [0u8; 32];
-        hasher.input(data);
-        hasher.result(&mut hash_bytes);
-        let hash = Hash::Sha3(hash_bytes);
-        hash
+        algorithm.hash_bytes(write.as_slice())
     }
 }
 
@@ -138,11 +213,62 @@ macro_rules! hashable_for_writable {
             fn as_hash(&self) -> Hash {
                 self.writable_to_hash()
             }
+
+            fn as_hash_with(&self, algorithm: HashAlgorithm) -> Hash {
+                self.writable_to_hash_with(algorithm)
+            }
         }
     }
 }
 
 
+/// Provides the values of child hashes while a Readable is read.
+///
+/// Writable types only write the hashes of their childs, so reading them
+/// again requires a source for the childs.  Every ObjectStore and HashIO
+/// can be used.
+pub trait ChildReader {
+    fn read_child<T: Readable>(&self, hash: &Hash) -> Result<T, HashIOError>;
+}
+
+/// Read itself from any read trait.
+///
+/// It's the counterpart of Writable and reads the format which write_to
+/// has written.
+pub trait Readable: Sized {
+    fn read_from<R: Read, C: ChildReader>(read: &mut R, childs: &C) -> Result<Self, HashIOError>;
+
+    /// Cheap value which can stand in for a child which is not read.
+    ///
+    /// Readers which only walk the childs, like the garbage collection,
+    /// use it to skip childs they have seen already.  Types without such
+    /// a value return None and are always read.
+    fn placeholder() -> Option<Self> {
+        None
+    }
+}
+
+/// Types which write a version number in front of their data.
+///
+/// Readers use the version to read older data of the type.
+pub trait Versioned {
+    /// Version which is written by write_to.
+    fn version() -> u32;
+}
+
+/// ChildReader for types which don't have childs.
+///
+/// It fails if a child is requested.
+pub struct NoChilds;
+
+impl ChildReader for NoChilds {
+    fn read_child<T: Readable>(&self, hash: &Hash) -> Result<T, HashIOError> {
+        Err(HashIOError::Undefined(format!("No childs available, requested: {}",
+                                           hash.as_string())))
+    }
+}
+
+
 pub fn usize_to_u32_bytes(x: usize) -> [u8; 4] {
     let as_u32 = x as u32;
     let mut res = [0u8; 4];
@@ -151,11 +277,11 @@ pub fn usize_to_u32_bytes(x: usize) -> [u8; 4] {
 }
 
 pub fn read_bytes(reader: &mut Read, n: usize) -> Result<Vec<u8>, io::Error> {
-    let mut buffer: [u8; 1] = [0; 1];
-    let mut res: Vec<u8> = Vec::with_capacity(n);
-    for _ in 0..n {
-        try!(reader.read(&mut buffer));
-        res.push(buffer[0]);
+    // Don't trust n for the allocation, it could come from a corrupted length
+    let mut res: Vec<u8> = Vec::new();
+    try!(Read::take(reader, n as u64).read_to_end(&mut res));
+    if res.len() < n {
+        return Err(truncated_error(n, res.len()));
     }
     Ok(res)
 }
@@ -164,21 +290,26 @@ pub fn write_hash<W>(hash: &Hash, write: &mut W) -> Result<usize, io::Error> whe
     let bytes = hash.get_bytes();
     try!(write_u8(match hash {
         &Hash::None => 0,
-        &Hash::Sha3(_) => 1
+        &Hash::Sha3(_) => 1,
+        &Hash::Blake2b(_) => 2,
+        &Hash::Sha256(_) => 3
     }, write));
     write.write(&*bytes)
 }
 
 pub fn read_hash<R>(read: &mut R) -> Result<Hash, io::Error> where R: Read {
     let identifier = try!(read_u8(read));
-    match identifier {
-        1 => {
-            let mut bytes = [0u8; 32];
-            try!(read.read(&mut bytes));
-            Ok(Hash::Sha3(bytes))
-        }
-        _ => Ok(Hash::None)
-    }
+    let algorithm = match identifier {
+        0 => return Ok(Hash::None),
+        1 => HashAlgorithm::Sha3,
+        2 => HashAlgorithm::Blake2b,
+        3 => HashAlgorithm::Sha256,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       format!("Unknown hash algorithm: {}", identifier)))
+    };
+    let mut bytes = [0u8; 32];
+    try!(read_exactly(read, &mut bytes));
+    Ok(algorithm.hash_from_bytes(bytes))
 }
 
 pub fn write_tm<W>(tm: Tm, write: &mut W) -> Result<usize, io::Error> where W: Write {
@@ -222,4 +353,61 @@ pub fn read_tm<R>(read: &mut R) -> Result<Tm, io::Error> where R: Read {
         tm_utcoff: utcoff,
         tm_nsec: nsec
     })
-}
\ No newline at end of file
+}
+
+
+/// Implement Writable, Readable and Hashable for a primitive type
+///
+/// The value is written by the given io function, so its hash only
+/// depends on the byte representation.
+macro_rules! writable_for_primitive {
+    ($prim_type:path, $write_fn:ident, $read_fn:ident, $placeholder:expr) => {
+        impl Writable for $prim_type {
+            fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
+                $write_fn(*self, write)
+            }
+        }
+
+        impl Readable for $prim_type {
+            fn read_from<R: Read, C: ChildReader>(read: &mut R, _: &C)
+                    -> Result<$prim_type, HashIOError> {
+                Ok(try!($read_fn(read)))
+            }
+
+            fn placeholder() -> Option<$prim_type> {
+                Some($placeholder)
+            }
+        }
+
+        hashable_for_writable!($prim_type);
+    }
+}
+
+writable_for_primitive!(u8, write_u8, read_u8, 0);
+writable_for_primitive!(u32, write_u32, read_u32, 0);
+writable_for_primitive!(u64, write_u64, read_u64, 0);
+writable_for_primitive!(i16, write_i16, read_i16, 0);
+writable_for_primitive!(i32, write_i32, read_i32, 0);
+writable_for_primitive!(f32, write_f32, read_f32, 0.0);
+writable_for_primitive!(Tm, write_tm, read_tm, empty_tm());
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn truncated_test() {
+        let mut read: &[u8] = &[0, 0, 1];
+        let err = read_u32(&mut read).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        assert_eq!(true, is_truncated(&err));
+
+        let mut read: &[u8] = &[1, 2, 3];
+        assert_eq!(vec![1, 2], read_bytes(&mut read, 2).unwrap());
+        assert_eq!(true, is_truncated(&read_bytes(&mut read, 2).unwrap_err()));
+
+        let mut read: &[u8] = &[1, 2, 3];
+        assert_eq!(true, is_truncated(&read_hash(&mut read).unwrap_err()));
+    }
+}
@@ -4,40 +4,255 @@ use hash::*;
 use hashio::*;
 use io::*;
 use log::*;
+use merkle::*;
+use sign::*;
+use checkpoint::*;
+use store::*;
+use std::cell::{Ref, RefCell};
+use std::collections::BTreeSet;
 use std::io;
 use std::io::{Write, Read};
-use std::fs::{File};
 use self::time::{now};
 
+/// Name of the branch which is used by default.
+///
+/// It's also written to the `head` reference, so logs created before
+/// branches existed open on it.
+pub const MAIN_BRANCH: &'static str = "main";
+
+/// Name of the reference which points to the head of the branch.
+pub fn branch_ref(name: &str) -> String {
+    format!("refs/{}", name)
+}
+
+/// Read the head of the branch from the store.
+///
+/// The main branch falls back to the `head` reference for logs which
+/// were written before branches existed.
+pub fn read_branch_head(store: &ObjectStore, name: &str) -> Result<Option<Hash>, HashIOError> {
+    let hash = try!(store.read_ref(&branch_ref(name)));
+    if hash.is_none() && name == MAIN_BRANCH {
+        return store.read_ref("head");
+    }
+    Ok(hash)
+}
+
+/// Let the branch reference point to the hash.
+///
+/// For the main branch, the `head` reference and a backup with the
+/// current time are written too.
+pub fn write_branch_head(store: &ObjectStore, name: &str, hash: &Hash) -> Result<(), HashIOError> {
+    try!(store.write_ref(&branch_ref(name), hash));
+    if name == MAIN_BRANCH {
+        let timestamp = format!("head-{}", time::now().rfc3339());
+        try!(store.write_ref("head", hash));
+        try!(store.write_ref(&timestamp, hash));
+    }
+    Ok(())
+}
+
+/// Prefix of the references which keep heads that were overwritten.
+///
+/// The full name is the prefix followed by the branch and the time the
+/// head was replaced, like `backups/feature/<timestamp>`.
+pub const BACKUP_REF_PREFIX: &'static str = "backups/";
+
+/// Keep the old head of a branch before it's overwritten.
+///
+/// Used when a forced sync or import moves a branch away from entries
+/// which would get lost otherwise.  The backup keeps them from being
+/// garbage collected just like the `head-<timestamp>` references.
+pub fn write_branch_backup(store: &ObjectStore, name: &str, hash: &Hash) -> Result<(), HashIOError> {
+    let backup = format!("{}{}/{}", BACKUP_REF_PREFIX, name, time::now().rfc3339());
+    store.write_ref(&backup, hash)
+}
+
+/// Branch names consist of path segments separated by slashes.
+///
+/// The segments may only contain alphanumeric characters, dashes,
+/// underscores and dots and must not be `.` or `..`.
+pub fn is_valid_branch_name(name: &str) -> bool {
+    name.split('/').all(|part| !part.is_empty() && part != "." && part != ".."
+        && part.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.'))
+}
+
+/// Prefix of the references which record the anchors of a shallow log.
+///
+/// The full name is the prefix followed by the hash of the anchor.
+pub const ANCHOR_REF_PREFIX: &'static str = "shallow-";
+
+/// Read all anchors of a shallow log from the store.
+pub fn read_anchors(store: &ObjectStore) -> Result<BTreeSet<Hash>, HashIOError> {
+    let mut result = BTreeSet::new();
+    for name in try!(store.list_refs()) {
+        if name.starts_with(ANCHOR_REF_PREFIX) {
+            if let Some(hash) = try!(store.read_ref(&name)) {
+                result.insert(hash);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Record the hash as anchor of a shallow log.
+pub fn write_anchor(store: &ObjectStore, hash: &Hash) -> Result<(), HashIOError> {
+    store.write_ref(&anchor_ref(hash), hash)
+}
+
+/// Remove the anchor from the store.
+pub fn delete_anchor(store: &ObjectStore, hash: &Hash) -> Result<(), HashIOError> {
+    store.delete_ref(&anchor_ref(hash))
+}
+
+fn anchor_ref(hash: &Hash) -> String {
+    format!("{}{}", ANCHOR_REF_PREFIX, hash.as_string())
+}
+
 impl From<HashIOError> for LogError {
-    fn from(_: HashIOError) -> LogError {
-        LogError::Unknown
+    fn from(err: HashIOError) -> LogError {
+        LogError::from_hashio_error(Hash::None, err)
     }
 }
 
 pub struct IOLogItem<T>
         where T: Hashable,
               HashIO: HashIOImpl<T> {
-    parent_hash: Hash,
-    item: T
+    pub parent_hash: Hash,
+
+    /// Hash under which the item is stored.
+    ///
+    /// It's kept separately because an item which was migrated from an
+    /// older version calculates to a different hash.
+    pub item_hash: Hash,
+    pub item: T,
+
+    /// Further parents if the item merges other histories.
+    pub merge_hashes: Vec<Hash>,
+
+    /// Signature of the signed_hash.
+    ///
+    /// It's written after the hashes, so unsigned items keep their format.
+    pub signature: Option<EntrySignature>
 }
 
-impl<T> Writable for IOLogItem<T>
+/// Marker in front of the optional signature of a stored log item.
+const SIGNATURE_MARKER: u8 = 1;
+
+/// Marker in front of the optional merge hash of a stored log item.
+const MERGE_MARKER: u8 = 2;
+
+/// Hashes a stored log item refers to.
+///
+/// Returns the parents and the item hash without reading the item itself.
+pub fn read_item_links<R: Read>(read: &mut R) -> Result<(Vec<Hash>, Hash), HashIOError> {
+    let (parent_hash, item_hash, merge_hashes, _) = try!(read_item_header(read));
+    let mut parents = Vec::new();
+    if parent_hash != Hash::None {
+        parents.push(parent_hash);
+    }
+    parents.extend(merge_hashes);
+    Ok((parents, item_hash))
+}
+
+/// Read everything of a stored log item except the item.
+///
+/// Optional parts follow the hashes, each of them starts with a marker.
+/// Every merged parent has its own marker.
+fn read_item_header<R: Read>(read: &mut R)
+        -> Result<(Hash, Hash, Vec<Hash>, Option<EntrySignature>), HashIOError> {
+    let parent_hash = try!(read_hash(read));
+    let item_hash = try!(read_hash(read));
+    let mut merge_hashes = Vec::new();
+    let mut signature = None;
+    loop {
+        match read_u8(read) {
+            Ok(SIGNATURE_MARKER) => signature = Some(try!(EntrySignature::read(read))),
+            Ok(MERGE_MARKER) => merge_hashes.push(try!(read_hash(read))),
+            Ok(marker) => return Err(HashIOError::Undefined(
+                format!("Unknown log item marker: {}", marker))),
+            Err(ref err) if is_truncated(err) => break,
+            Err(err) => return Err(HashIOError::IOError(err))
+        }
+    }
+    Ok((parent_hash, item_hash, merge_hashes, signature))
+}
+
+
+impl<T> IOLogItem<T>
         where T: Hashable,
               HashIO: HashIOImpl<T> {
-    fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
+    pub fn new(parent_hash: Hash, item: T) -> IOLogItem<T> {
+        IOLogItem::with_algorithm(parent_hash, item, HashAlgorithm::default())
+    }
+
+    /// Create a log item whose item is hashed with the given algorithm.
+    ///
+    /// The log item itself is hashed with the same algorithm.
+    pub fn with_algorithm(parent_hash: Hash, item: T, algorithm: HashAlgorithm) -> IOLogItem<T> {
+        IOLogItem {
+            parent_hash: parent_hash,
+            item_hash: item.as_hash_with(algorithm),
+            item: item,
+            merge_hashes: Vec::new(),
+            signature: None
+        }
+    }
+
+    /// Hash of the item without its signature which is signed.
+    ///
+    /// The item itself is covered by its item hash.  IOLog checks the
+    /// stored item against it whenever the entry is read, so a signature
+    /// is never accepted for a replaced item.
+    pub fn signed_hash(&self) -> Hash {
+        let mut bytes = Vec::new();
+        self.write_unsigned(&mut bytes).expect("Writing to a vec should not cause any issues");
+        self.item_hash.algorithm().unwrap_or_default().hash_bytes(&bytes)
+    }
+
+    /// Hashes of the parent and the merged items.
+    pub fn parent_hashes(&self) -> Vec<Hash> {
+        let mut result = Vec::new();
+        if self.parent_hash != Hash::None {
+            result.push(self.parent_hash);
+        }
+        result.extend(self.merge_hashes.iter().cloned());
+        result
+    }
+
+    fn write_unsigned<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
         let mut size = 0;
         size += try!(write_hash(&self.parent_hash, write));
-        try!(write_hash(&self.item.as_hash(), write));
+        try!(write_hash(&self.item_hash, write));
         size += 32;
+        for merge_hash in self.merge_hashes.iter() {
+            size += try!(write_u8(MERGE_MARKER, write));
+            size += try!(write_hash(merge_hash, write));
+        }
+        Ok(size)
+    }
+}
+
+impl<T> Writable for IOLogItem<T>
+        where T: Hashable,
+              HashIO: HashIOImpl<T> {
+    fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
+        let mut size = try!(self.write_unsigned(write));
+        if let Some(ref signature) = self.signature {
+            size += try!(write_u8(SIGNATURE_MARKER, write));
+            size += try!(signature.write_to(write));
+        }
         Ok(size)
     }
 }
 impl<T> Hashable for IOLogItem<T>
         where T: Hashable,
               HashIO: HashIOImpl<T> {
+    /// Hash of the log item.
+    ///
+    /// It uses the algorithm of the item hash, so the hash of a log item
+    /// never depends on the HashIO which reads it.
     fn as_hash(&self) -> Hash {
-        self.writable_to_hash()
+        self.writable_to_hash_with(self.item_hash.algorithm().unwrap_or_default())
     }
 }
 
@@ -46,15 +261,14 @@ impl<T> HashIOImpl<IOLogItem<T>> for HashIO
               HashIO: HashIOImpl<T> {
     fn receive_hashable<R>(&self, read: &mut R) -> Result<IOLogItem<T>, HashIOError>
             where R: Read {
-        let parent_hash = try!(read_hash(read));
-        let item;
-        {
-            let hash_val = try!(read_hash(read));
-            item = try!(self.get(&hash_val));
-        }
+        let (parent_hash, item_hash, merge_hashes, signature) = try!(read_item_header(read));
+        let item = try!(self.get(&item_hash));
         Ok(IOLogItem {
             parent_hash: parent_hash,
-            item: item
+            item_hash: item_hash,
+            item: item,
+            merge_hashes: merge_hashes,
+            signature: signature
         })
     }
 
@@ -74,57 +288,162 @@ pub struct IOLog<T>
         where T: Hashable,
               HashIO: HashIOImpl<T> {
     pub head: Option<IOLogItem<T>>,
-    pub hashio: HashIO
+    pub hashio: HashIO,
+
+    /// Built on first use, so opening a log doesn't walk its history.
+    merkle: RefCell<Option<MerkleTree>>,
+    signing_key: Option<SigningKey>,
+    branch: String,
+    anchors: BTreeSet<Hash>
 }
 
 impl<T> IOLog<T>
         where T: Hashable,
               HashIO: HashIOImpl<T> {
-    pub fn write_head(&self) -> Result<(), io::Error> {
+    /// Write the reference of the current branch.
+    ///
+    /// On the main branch, the head reference and a backup with the current
+    /// time are written too.  If the log has a signing key, a signed
+    /// checkpoint of the main head is written as well.
+    ///
+    /// The checkpoint is written before the references.  If the references
+    /// can't be written, the previous checkpoint is restored, so a failure
+    /// leaves the head of the store unchanged.
+    pub fn write_head(&self) -> Result<(), HashIOError> {
         if self.head.is_some() {
-            let now = time::now();
-            let hashio = &self.hashio;
-            let timestamp = format!("{}/head-{}", hashio.base_path, now.rfc3339());
             let hash = self.head.as_ref().unwrap().as_hash();
-            let filename =  format!("{}/head", hashio.base_path);
-            let mut file = try!(File::create(filename));
-            try!(write_hash(&hash, &mut file));
-            let mut backup = try!(File::create(timestamp));
-            try!(write_hash(&hash, &mut backup));
+            let store = self.hashio.store();
+            let key = match self.signing_key {
+                Some(ref key) if self.branch == MAIN_BRANCH => key,
+                _ => return write_branch_head(store, &self.branch, &hash)
+            };
+            let old_checkpoint = try!(store.read_ref(CHECKPOINT_REF));
+            let checkpoint = Checkpoint::new(hash, self.merkle_tree().len() as u64).sign(key);
+            try!(write_checkpoint(&self.hashio, &checkpoint));
+            if let Err(err) = write_branch_head(store, &self.branch, &hash) {
+                match old_checkpoint {
+                    Some(old_checkpoint) => store.write_ref(CHECKPOINT_REF, &old_checkpoint).ok(),
+                    None => store.delete_ref(CHECKPOINT_REF).ok()
+                };
+                return Err(err);
+            }
         }
         Ok(())
     }
 }
 
-impl<T> Log for IOLog<T>
+impl<T> IOLog<T>
         where T: Hashable,
               HashIO: HashIOImpl<T> {
-    type Item = T;
+    /// Load the log item of the hash and make sure it's not corrupted.
+    ///
+    /// The stored item must match its item hash as well, because the hash
+    /// of the log item is only built from the item hash.
+    fn get_item(&self, hash: &Hash) -> Result<IOLogItem<T>, LogError> {
+        let item = try!(self.hashio.get::<IOLogItem<T>>(hash)
+                .map_err(|err| LogError::from_hashio_error(*hash, err)));
+        let actual_hash = item.as_hash();
+        if actual_hash != *hash {
+            return Err(LogError::CorruptedEntry {
+                hash: *hash,
+                actual_hash: actual_hash
+            });
+        }
+        let actual_item_hash = try!(self.hashio.stored_hash(&item.item_hash)
+                .map_err(|err| LogError::from_hashio_error(item.item_hash, err)));
+        if actual_item_hash != item.item_hash {
+            return Err(LogError::CorruptedEntry {
+                hash: item.item_hash,
+                actual_hash: actual_item_hash
+            });
+        }
+        Ok(item)
+    }
 
-    /// Add new entry to the log
-    fn push(&mut self, hashable: T) -> Hash {
-        let new_head = IOLogItem {
-            parent_hash: match &self.head {
-                &Option::None => Hash::None,
-                &Option::Some(ref parent_item) => parent_item.as_hash()
-            },
-            item: hashable
-        };
-        let parent_hash = new_head.parent_hash.clone();
-        match self.hashio.put::<IOLogItem<T>>(&new_head) {
-            Ok(_) => (),
-            Err(_) => return Hash::None
+    /// Update the Merkle tree to the current head if it was built already.
+    ///
+    /// If an entry can't be read, the tree is dropped and built again on
+    /// its next use.
+    fn follow_merkle_head(&mut self) {
+        let merkle = self.merkle.get_mut().take();
+        if let Some(mut merkle) = merkle {
+            let head = self.head_hash();
+            if merkle.follow_head(&*self, head).is_ok() {
+                *self.merkle.get_mut() = Some(merkle);
+            }
         }
+    }
+
+    /// Sign the item, store it and make it the new head.
+    ///
+    /// The Merkle tree is updated with the entries since the last one it
+    /// shares with the new head.
+    fn push_item(&mut self, mut new_head: IOLogItem<T>) -> Result<Hash, LogError> {
+        if let Some(ref key) = self.signing_key {
+            new_head.signature = Some(key.sign(&new_head.signed_hash()));
+        }
+        let parent_hash = new_head.parent_hash.clone();
         let hash = new_head.as_hash();
+        try!(self.hashio.put::<IOLogItem<T>>(&new_head)
+                .map_err(|err| LogError::StorageError(hash, err)));
+        let extends_head = self.head_hash().unwrap_or(Hash::None) == parent_hash;
+        let old_head = self.head.take();
         self.head = Some(new_head);
-        match self.write_head() {
-            Ok(_) => (),
-            Err(_) => { return Hash::None }
-        };
+        if extends_head {
+            if let Some(ref mut merkle) = *self.merkle.get_mut() {
+                merkle.push(hash);
+            }
+        } else {
+            self.follow_merkle_head();
+        }
+        if let Err(err) = self.write_head() {
+            self.head = old_head;
+            self.follow_merkle_head();
+            return Err(LogError::StorageError(hash, err));
+        }
         if hash == parent_hash {
             print!("WARNING:  hash equals parent hash\n");
         }
-        hash
+        Ok(hash)
+    }
+}
+
+impl<T> Log for IOLog<T>
+        where T: Hashable,
+              HashIO: HashIOImpl<T> {
+    type Item = T;
+
+    /// Add new entry to the log
+    ///
+    /// The entry is stored first and then the head is written.
+    ///
+    /// # Errors
+    /// Throws an error if the entry or the head could not be written.  The
+    /// head stays unchanged in this case.
+    fn try_push(&mut self, hashable: T) -> Result<Hash, LogError> {
+        let new_head = IOLogItem::with_algorithm(match &self.head {
+                &Option::None => Hash::None,
+                &Option::Some(ref parent_item) => parent_item.as_hash()
+            }, hashable, self.hashio.algorithm());
+        self.push_item(new_head)
+    }
+
+    /// Add new entry with the given parents.
+    ///
+    /// # Errors
+    /// Throws an error if a parent can't be read or if the entry or the
+    /// head could not be written.
+    fn try_push_with_parents(&mut self, hashable: T, parents: Vec<Hash>)
+            -> Result<Hash, LogError> {
+        for parent in parents.iter() {
+            if !self.anchors.contains(parent) {
+                try!(self.get_item(parent));
+            }
+        }
+        let mut new_head = IOLogItem::with_algorithm(parents.first().cloned().unwrap_or(Hash::None),
+                                                     hashable, self.hashio.algorithm());
+        new_head.merge_hashes = parents.into_iter().skip(1).collect();
+        self.push_item(new_head)
     }
 
 
@@ -144,7 +463,7 @@ impl<T> Log for IOLog<T>
     /// # Errors
     /// Throws an error if an entry of the hash was not found.
     fn parent_hash(&self, hash: Hash) -> Result<Option<Hash>, LogError> {
-        let item: IOLogItem<T> = try!(self.hashio.get::<IOLogItem<T>>(&hash));
+        let item = try!(self.get_item(&hash));
         if item.parent_hash == hash {
             print!("WARNING: parent_hash detected redundancy\n");
         }
@@ -155,19 +474,73 @@ impl<T> Log for IOLog<T>
         res
     }
 
+    fn parent_hashes(&self, hash: Hash) -> Result<Vec<Hash>, LogError> {
+        Ok(try!(self.get_item(&hash)).parent_hashes())
+    }
+
     /// Get the borrowed entry of the given hash
     ///
     /// # Errors
     /// Throws an error if an entry of the hash was not found.
     fn get(&self, hash: Hash) -> Result<Self::Item, LogError> {
-        let item: IOLogItem<T> = try!(self.hashio.get::<IOLogItem<T>>(&hash));
+        let item = try!(self.get_item(&hash));
         Ok(item.item)
     }
 
+    /// The hash of a log item only depends on its stored content.
+    fn expected_hash(&self, hash: Hash) -> Result<Hash, LogError> {
+        let item = try!(self.hashio.get::<IOLogItem<T>>(&hash)
+                .map_err(|err| LogError::from_hashio_error(hash, err)));
+        Ok(item.as_hash())
+    }
+
+    fn signature(&self, hash: Hash) -> Result<Option<(Hash, EntrySignature)>, LogError> {
+        let item = try!(self.get_item(&hash));
+        Ok(item.signature.map(|signature| (item.signed_hash(), signature)))
+    }
+
     // Set defferent head
     fn reset_head(&mut self, hash: &Hash) -> Result<(), LogError> {
-        let item: IOLogItem<T> = try!(self.hashio.get::<IOLogItem<T>>(&hash));
+        let item = try!(self.get_item(&hash));
         self.head = Some(item);
+        self.follow_merkle_head();
+        Ok(())
+    }
+
+    fn anchor_hashes(&self) -> Vec<Hash> {
+        self.anchors.iter().cloned().collect()
+    }
+
+    fn is_anchor(&self, hash: Hash) -> bool {
+        self.anchors.contains(&hash)
+    }
+
+    /// The anchor is written as reference to the store.
+    fn add_anchor(&mut self, hash: Hash) -> Result<(), LogError> {
+        try!(write_anchor(self.hashio.store(), &hash)
+            .map_err(|err| LogError::StorageError(hash, err)));
+        self.anchors.insert(hash);
+        if let Some(ref mut merkle) = *self.merkle.get_mut() {
+            merkle.cut(&hash);
+        }
+        Ok(())
+    }
+
+    /// The anchor reference is removed from the store.
+    fn remove_anchor(&mut self, hash: Hash) -> Result<(), LogError> {
+        if !self.anchors.remove(&hash) {
+            return Ok(());
+        }
+        let result = self.history_since(None, hash).and_then(|_| {
+            delete_anchor(self.hashio.store(), &hash)
+                .map_err(|err| LogError::StorageError(hash, err))
+        });
+        if let Err(err) = result {
+            self.anchors.insert(hash);
+            return Err(err);
+        }
+        // The older entries are added in front of the tree
+        *self.merkle.get_mut() = None;
         Ok(())
     }
 }
@@ -175,24 +548,280 @@ impl<T> Log for IOLog<T>
 impl<T> IOLog<T>
         where T: Hashable,
             HashIO: HashIOImpl<T> {
+    /// Open the log in the given directory.
+    ///
+    /// The pack of the directory is used if it has one.  If the pack
+    /// can't be opened, the loose objects are used instead.  Use
+    /// `with_hashio(HashIO::open(path)?)` to get the error.
     pub fn new(path: String) -> IOLog<T> {
-        let hashio = HashIO::new(path.clone());
-        let filename = format!("{}/head", path.clone());
-        let hash = match File::open(filename) {
-            Ok(mut file) => read_hash(&mut file).unwrap_or(Hash::None),
-            Err(_) => Hash::None
+        let hashio = match HashIO::open(path.clone()) {
+            Ok(hashio) => hashio,
+            Err(_) => HashIO::new(path)
         };
+        IOLog::with_hashio(hashio)
+    }
+
+    /// Open the log stored in the given HashIO.
+    ///
+    /// The head is loaded from the `head` reference of its store.
+    pub fn with_hashio(hashio: HashIO) -> IOLog<T> {
+        let hash = hashio.store().read_ref("head").unwrap_or(None);
         let head = match hash {
-            Hash::None => Option::None,
-            _ => hashio.get::<IOLogItem<T>>(&hash).ok()
+            Option::None => Option::None,
+            Some(hash) => hashio.get::<IOLogItem<T>>(&hash).ok()
         };
-        IOLog{
+        let anchors = read_anchors(hashio.store()).unwrap_or_default();
+        IOLog {
             head: head,
-            hashio: HashIO::new(path)
+            hashio: hashio,
+            merkle: RefCell::new(None),
+            signing_key: None,
+            branch: MAIN_BRANCH.to_string(),
+            anchors: anchors
         }
     }
 }
 
+impl<T> IOLog<T>
+        where T: Hashable,
+              HashIO: HashIOImpl<T> {
+    /// Sign every new entry and checkpoint with the key.
+    pub fn with_signing_key(mut self, key: SigningKey) -> IOLog<T> {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// Open the log in the directory only if its head is checkpointed.
+    ///
+    /// See with_hashio_checked.
+    pub fn new_checked(path: String, trusted_keys: &[PublicKey]) -> Result<IOLog<T>, LogError> {
+        let hashio = try!(HashIO::open(path).map_err(|err| LogError::StorageError(Hash::None, err)));
+        IOLog::with_hashio_checked(hashio, trusted_keys)
+    }
+
+    /// Open the log only if its head is checkpointed.
+    ///
+    /// # Errors
+    /// Fails with InvalidCheckpoint if the latest checkpoint is missing,
+    /// not signed by one of the trusted keys or doesn't match the head.
+    pub fn with_hashio_checked(hashio: HashIO, trusted_keys: &[PublicKey])
+            -> Result<IOLog<T>, LogError> {
+        IOLog::open_checked(hashio, trusted_keys, None)
+    }
+
+    /// Open the log only if its checkpoint is newer than the given one.
+    ///
+    /// The last trusted checkpoint must be remembered by the caller, for
+    /// example by reading it with read_checkpoint after the log was
+    /// opened.  This detects a log which was rolled back to an older but
+    /// validly signed checkpoint.
+    ///
+    /// # Errors
+    /// Fails like with_hashio_checked and with InvalidCheckpoint if the
+    /// checkpoint has fewer entries or an earlier timestamp than the last
+    /// trusted one.
+    pub fn with_hashio_checked_after(hashio: HashIO, trusted_keys: &[PublicKey],
+                                     last_trusted: &Checkpoint)
+            -> Result<IOLog<T>, LogError> {
+        IOLog::open_checked(hashio, trusted_keys, Some(last_trusted))
+    }
+
+    fn open_checked(hashio: HashIO, trusted_keys: &[PublicKey], last_trusted: Option<&Checkpoint>)
+            -> Result<IOLog<T>, LogError> {
+        let checkpoint = match try!(read_checkpoint(&hashio)) {
+            Some(checkpoint) => checkpoint,
+            None => return Err(LogError::InvalidCheckpoint("No checkpoint found".to_string()))
+        };
+        if !checkpoint.is_trusted(trusted_keys) {
+            return Err(LogError::InvalidCheckpoint(
+                "Checkpoint is not signed by a trusted key".to_string()));
+        }
+        if let Some(last_trusted) = last_trusted {
+            if !checkpoint.follows(last_trusted) {
+                return Err(LogError::InvalidCheckpoint(
+                    format!("Checkpoint of {} is older than the last trusted one",
+                            checkpoint.head_hash)));
+            }
+        }
+        let log = IOLog::with_hashio(hashio);
+        if log.head_hash() != Some(checkpoint.head_hash)
+                || log.merkle_tree().len() as u64 != checkpoint.entry_count {
+            return Err(LogError::InvalidCheckpoint(
+                format!("Head doesn't match the checkpoint of {}", checkpoint.head_hash)));
+        }
+        Ok(log)
+    }
+}
+
+impl<T> IOLog<T>
+        where T: Hashable,
+              HashIO: HashIOImpl<T> {
+    /// Name of the branch new entries are added to.
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// Head hash of the branch or None if it doesn't exist.
+    pub fn branch_head(&self, name: &str) -> Result<Option<Hash>, LogError> {
+        Ok(try!(read_branch_head(self.hashio.store(), name)))
+    }
+
+    /// Names of all branches.
+    pub fn list_branches(&self) -> Result<Vec<String>, LogError> {
+        let mut result: Vec<String> = try!(self.hashio.store().list_refs()).into_iter()
+            .filter(|name| name.starts_with("refs/"))
+            .map(|name| name[5..].to_string())
+            .collect();
+        if !result.iter().any(|name| name == MAIN_BRANCH)
+                && try!(self.branch_head(MAIN_BRANCH)).is_some() {
+            result.push(MAIN_BRANCH.to_string());
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    /// Create a branch which starts at the current head.
+    ///
+    /// The current branch doesn't change.
+    ///
+    /// # Errors
+    /// Fails if the name is invalid, if the branch exists already or if
+    /// the log is empty.
+    pub fn create_branch(&self, name: &str) -> Result<(), LogError> {
+        if !is_valid_branch_name(name) {
+            return Err(LogError::InvalidBranch(format!("Invalid branch name: {}", name)));
+        }
+        if try!(self.branch_head(name)).is_some() {
+            return Err(LogError::BranchExists(name.to_string()));
+        }
+        match self.head_hash() {
+            None => Err(LogError::InvalidBranch("Cannot branch an empty log".to_string())),
+            Some(hash) => {
+                try!(self.hashio.store().write_ref(&branch_ref(name), &hash));
+                Ok(())
+            }
+        }
+    }
+
+    /// Continue the log on the head of another branch.
+    pub fn switch_branch(&mut self, name: &str) -> Result<(), LogError> {
+        match try!(self.branch_head(name)) {
+            None => Err(LogError::BranchNotFound(name.to_string())),
+            Some(hash) => {
+                try!(self.reset_head(&hash));
+                self.branch = name.to_string();
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove the reference of the branch.
+    ///
+    /// Its entries stay in the store until they are garbage collected.
+    ///
+    /// # Errors
+    /// The main and the current branch can't be deleted.
+    pub fn delete_branch(&self, name: &str) -> Result<(), LogError> {
+        if name == MAIN_BRANCH || name == self.branch {
+            return Err(LogError::InvalidBranch(
+                format!("Cannot delete the main or the current branch: {}", name)));
+        }
+        if try!(self.branch_head(name)).is_none() {
+            return Err(LogError::BranchNotFound(name.to_string()));
+        }
+        try!(self.hashio.store().delete_ref(&branch_ref(name)));
+        Ok(())
+    }
+}
+
+impl<T> IOLog<T>
+        where T: Hashable,
+              HashIO: HashIOImpl<T> {
+    /// Cut the history of the head down to the newest entries.
+    ///
+    /// The parents where the history is cut are recorded as anchors, see
+    /// shallow_anchors.  The older entries stay in the store until they
+    /// are garbage collected.  Backups of older heads are kept, so use
+    /// `GarbageCollection::without_backups` to get rid of them.
+    ///
+    /// Returns the new anchors.
+    ///
+    /// # Errors
+    /// The anchors are global to the store, so it fails with
+    /// InvalidBranch if another branch forks off behind them.
+    pub fn truncate(&mut self, keep: usize) -> Result<Vec<Hash>, LogError> {
+        let head = match self.head_hash() {
+            None => return Ok(Vec::new()),
+            Some(hash) => hash
+        };
+        let anchors = try!(shallow_anchors(&*self, head, keep));
+        let mut dropped = BTreeSet::new();
+        for anchor in anchors.iter() {
+            dropped.extend(try!(self.history_since(None, *anchor)));
+        }
+        let kept: BTreeSet<Hash> = try!(self.history_since(None, head)).into_iter()
+            .filter(|hash| !dropped.contains(hash))
+            .collect();
+        for name in try!(self.list_branches()) {
+            if name == self.branch {
+                continue;
+            }
+            let mut visited = BTreeSet::new();
+            let mut pending: Vec<Hash> = try!(self.branch_head(&name)).into_iter().collect();
+            while let Some(hash) = pending.pop() {
+                if dropped.contains(&hash) {
+                    return Err(LogError::InvalidBranch(
+                        format!("Branch {} forks off behind the new anchors", name)));
+                }
+                if kept.contains(&hash) || self.is_anchor(hash) || !visited.insert(hash) {
+                    continue;
+                }
+                pending.extend(try!(self.parent_hashes(hash)));
+            }
+        }
+        for anchor in anchors.iter() {
+            try!(self.add_anchor(*anchor));
+        }
+        Ok(anchors)
+    }
+
+    /// Remove all anchors whose history is available again.
+    ///
+    /// Anchors whose entries are still missing are kept.  Returns the
+    /// removed anchors.
+    ///
+    /// # Errors
+    /// Fails if an anchor reference could not be removed.
+    pub fn unshallow(&mut self) -> Result<Vec<Hash>, LogError> {
+        let mut removed = Vec::new();
+        for anchor in self.anchor_hashes() {
+            match self.remove_anchor(anchor) {
+                Ok(()) => removed.push(anchor),
+                Err(LogError::EntryNotFound(_)) => (),
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl<T> MerkleLog for IOLog<T>
+        where T: Hashable,
+              HashIO: HashIOImpl<T> {
+    /// The tree is built from the history of the head on the first call.
+    ///
+    /// It stays empty if an entry of the history can't be read.
+    fn merkle_tree(&self) -> Ref<MerkleTree> {
+        if self.merkle.borrow().is_none() {
+            let algorithm = self.hashio.algorithm();
+            let merkle = MerkleTree::from_log(self, algorithm)
+                .unwrap_or_else(|_| MerkleTree::new(algorithm));
+            *self.merkle.borrow_mut() = Some(merkle);
+        }
+        Ref::map(self.merkle.borrow(), |merkle| merkle.as_ref().expect("The tree was built"))
+    }
+}
+
 
 #[cfg(test)]
 mod test {
@@ -200,10 +829,12 @@ mod test {
     use super::super::hashio::*;
     use super::super::io::*;
     use super::super::log::*;
+    use super::super::merkle::*;
     use super::*;
     use std::io::{Read, Write};
+    use super::super::store::*;
+    use super::super::gc::*;
     use std::io;
-    use std::fs::remove_dir_all;
 
     tbd_model!(A, [
         [a: u8, write_u8, read_u8]
@@ -213,8 +844,8 @@ mod test {
 
     #[test]
     fn test() {
-        remove_dir_all("logtest").ok();
-        let mut log = IOLog::<A>::new("logtest".to_string());
+        let store = MemoryStore::new();
+        let mut log = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
         // make sure the log is empty
         assert_eq!(None, log.head_hash());
 
@@ -231,13 +862,17 @@ mod test {
         assert_eq!(two, two_ref);
 
         // Verify if reloading works correcty
-        let log2 = IOLog::<A>::new("logtest".to_string());
+        let log2 = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
         let two_ref2: A = log.get(log2.head_hash().unwrap()).ok().unwrap();
         assert_eq!(two, two_ref2);
 
 
-        let log3 = IOLog::<A>::new("logtest".to_string());
+        let log3 = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
         assert_eq!(Ok(Some(hash_one)), log3.parent_hash(hash_two));
+        // The Merkle tree is rebuilt when the log is loaded
+        assert_eq!(log.merkle_root(), log3.merkle_root());
+        let proof = log3.inclusion_proof(&hash_one).unwrap();
+        assert_eq!(true, verify_inclusion(&proof, &log.merkle_root()));
 
         let mut hash_iter = LogIteratorHash::from_log(&log3);
         print!("Hash two\n");
@@ -251,4 +886,373 @@ mod test {
         assert_eq!(Some(one), iter.next());
         assert_eq!(None, iter.next());
     }
+
+    #[test]
+    fn error_test() {
+        use std::error::Error;
+
+        let store = MemoryStore::new();
+        let mut log = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        let hash_one = log.push(A{a: 1, b: "one".to_string()});
+
+        let missing = Hash::hash_bytes(b"missing");
+        assert_eq!(Err(LogError::EntryNotFound(missing)), log.get(missing));
+
+        let broken = Hash::hash_bytes(b"broken");
+        store.put(&broken, b"bad").unwrap();
+        match log.get(broken) {
+            Err(ref err @ LogError::StorageError(_, HashIOError::IOError(_))) =>
+                assert_eq!(true, err.source().is_some()),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+
+        let copied = Hash::hash_bytes(b"copied");
+        store.put(&copied, &store.get(&hash_one).unwrap()).unwrap();
+        assert_eq!(Err(LogError::CorruptedEntry { hash: copied, actual_hash: hash_one }),
+                   log.parent_hash(copied));
+    }
+
+    #[test]
+    fn payload_test() {
+        let store = MemoryStore::new();
+        let mut log = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        let one = A{a: 1, b: "one".to_string()};
+        let hash_one = log.push(one.clone());
+        log.push(A{a: 2, b: "two".to_string()});
+        assert_eq!(None, verify_log(&log));
+
+        let item_hash = one.as_hash();
+        let evil = A{a: 1, b: "EVIL".to_string()};
+        let mut bytes = Vec::new();
+        log.hashio.put(&evil.b).unwrap();
+        evil.write_to(&mut bytes).unwrap();
+        store.put(&item_hash, &bytes).unwrap();
+
+        let corrupted = || LogError::CorruptedEntry { hash: item_hash, actual_hash: evil.as_hash() };
+        assert_eq!(Err(corrupted()), log.get(hash_one));
+        assert_eq!(Some(LogVerifyFailure::LogError(corrupted())), verify_log(&log));
+    }
+
+    mod v0 {
+        use super::*;
+
+        tbd_model!(A, [
+            [a: u8, write_u8, read_u8]
+         ], [
+            [b: String]
+         ]);
+    }
+
+    mod v1 {
+        use super::*;
+
+        fn migrate_a<R, C>(version: u32, read: &mut R, childs: &C) -> Result<A, HashIOError>
+                where R: Read, C: ChildReader {
+            match version {
+                0 => {
+                    let a = try!(read_u8(read));
+                    let b = try!(childs.read_child(&try!(read_hash(read))));
+                    Ok(A { a: a, c: 0, b: b })
+                },
+                _ => Err(HashIOError::UnsupportedVersion(version))
+            }
+        }
+
+        tbd_model!(A, version 1, migrate migrate_a, [
+            [a: u8, write_u8, read_u8],
+            [c: u8, write_u8, read_u8]
+         ], [
+            [b: String]
+         ]);
+    }
+
+    #[test]
+    fn migration_test() {
+
+        let store = MemoryStore::new();
+        let hash_one;
+        let hash_two;
+        {
+            let mut log = IOLog::<v0::A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+            hash_one = log.push(v0::A{a: 1, b: "one".to_string()});
+            hash_two = log.push(v0::A{a: 2, b: "two".to_string()});
+        }
+
+        let mut log = IOLog::<v1::A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        assert_eq!(Some(hash_two), log.head_hash());
+        assert_eq!(Ok(Some(hash_one)), log.parent_hash(hash_two));
+        assert_eq!(Ok(v1::A{a: 1, c: 0, b: "one".to_string()}), log.get(hash_one));
+        let hash_three = log.push(v1::A{a: 3, c: 3, b: "three".to_string()});
+        assert_eq!(Ok(Some(hash_two)), log.parent_hash(hash_three));
+
+        // The old objects are still reachable
+        let report = GarbageCollection::new().dry_run()
+            .collect::<v1::A>(&log.hashio).unwrap();
+        assert_eq!(0, report.unreachable.len());
+
+        // Old readers can't read the new version
+        let old_log = IOLog::<v0::A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        match old_log.get(hash_three) {
+            Err(LogError::StorageError(_, HashIOError::UnsupportedVersion(1))) => (),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn signature_test() {
+        use sign::*;
+
+        let store = MemoryStore::new();
+        let key = SigningKey::from_seed([3u8; 32]);
+        let unsigned = {
+            let mut log = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+            log.push(A{a: 1, b: "one".to_string()})
+        };
+        let signed = {
+            let mut log = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())))
+                .with_signing_key(key.clone());
+            log.push(A{a: 2, b: "two".to_string()})
+        };
+
+        // Signatures are read from the store again
+        let log = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        assert_eq!(Some(signed), log.head_hash());
+        assert_eq!(Ok(A{a: 2, b: "two".to_string()}), log.get(signed));
+        assert_eq!(None, verify_log(&log));
+        assert_eq!(Some(LogVerifyFailure::UnsignedEntry(unsigned)),
+                   verify_log_with_keys(&log, &[key.public_key()]));
+        let (signed_hash, signature) = log.signature(signed).unwrap().unwrap();
+        assert_eq!(true, signature.verify(&signed_hash));
+    }
+
+    #[test]
+    fn checkpoint_failure_test() {
+        use sign::*;
+
+        let store = FullStore::default();
+        let key = SigningKey::from_seed([5u8; 32]);
+        let mut log = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())))
+            .with_signing_key(key.clone());
+        let hash_one = log.push(A{a: 1, b: "one".to_string()});
+        let checkpoint = store.read_ref(CHECKPOINT_REF).unwrap();
+        assert_eq!(true, checkpoint.is_some());
+
+        // The entry is stored already, so only the checkpoint object fails
+        let mut item = IOLogItem::new(hash_one, A{a: 2, b: "two".to_string()});
+        item.signature = Some(key.sign(&item.signed_hash()));
+        log.hashio.put(&item).unwrap();
+        store.objects_full.set(true);
+        match log.try_push(A{a: 2, b: "two".to_string()}) {
+            Err(LogError::StorageError(hash, _)) => assert_eq!(item.as_hash(), hash),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+        assert_eq!(Some(hash_one), log.head_hash());
+        assert_eq!(Some(hash_one), store.read_ref("head").unwrap());
+        assert_eq!(Some(hash_one), store.read_ref(&branch_ref(MAIN_BRANCH)).unwrap());
+        assert_eq!(checkpoint, store.read_ref(CHECKPOINT_REF).unwrap());
+
+        store.objects_full.set(false);
+        let hash_two = log.try_push(A{a: 2, b: "two".to_string()}).unwrap();
+        assert_eq!(item.as_hash(), hash_two);
+        assert_eq!(Some(hash_two), store.read_ref("head").unwrap());
+    }
+
+    #[test]
+    fn signed_payload_test() {
+        use sign::*;
+
+        let store = MemoryStore::new();
+        let key = SigningKey::from_seed([4u8; 32]);
+        let mut log = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())))
+            .with_signing_key(key.clone());
+        let two = A{a: 2, b: "two".to_string()};
+        log.push(A{a: 1, b: "one".to_string()});
+        let hash_two = log.push(two.clone());
+        assert_eq!(None, verify_log_with_keys(&log, &[key.public_key()]));
+
+        let item_hash = two.as_hash();
+        let evil = A{a: 2, b: "EVIL".to_string()};
+        let mut bytes = Vec::new();
+        log.hashio.put(&evil.b).unwrap();
+        evil.write_to(&mut bytes).unwrap();
+        store.put(&item_hash, &bytes).unwrap();
+
+        let corrupted = || LogError::CorruptedEntry { hash: item_hash, actual_hash: evil.as_hash() };
+        assert_eq!(Some(LogVerifyFailure::LogError(corrupted())),
+                   verify_log_with_keys(&log, &[key.public_key()]));
+        match log.signature(hash_two) {
+            Err(ref err) if *err == corrupted() => (),
+            other => panic!("Unexpected signature: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn algorithm_test() {
+        let store = MemoryStore::new();
+        let hash_one = {
+            let mut log = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+            log.push(A{a: 1, b: "one".to_string()})
+        };
+        let hashio = HashIO::with_store(Box::new(store.clone())).with_algorithm(HashAlgorithm::Blake2b);
+        let mut log = IOLog::<A>::with_hashio(hashio);
+        let hash_two = log.push(A{a: 2, b: "two".to_string()});
+        assert_eq!(Some(HashAlgorithm::Sha3), hash_one.algorithm());
+        assert_eq!(Some(HashAlgorithm::Blake2b), hash_two.algorithm());
+
+        // Both algorithms can be read from the same repository
+        let log = IOLog::<A>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        assert_eq!(Ok(Some(hash_one)), log.parent_hash(hash_two));
+        assert_eq!(Ok(A{a: 2, b: "two".to_string()}), log.get(hash_two));
+        assert_eq!(6, log.hashio.store().list().unwrap().len());
+        let report = GarbageCollection::new().dry_run()
+            .collect::<A>(&log.hashio).unwrap();
+        assert_eq!(0, report.unreachable.len());
+    }
+
+    #[test]
+    fn branch_test() {
+        let store = MemoryStore::new();
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        assert_eq!(Err(LogError::InvalidBranch("Cannot branch an empty log".to_string())),
+                   log.create_branch("experiment"));
+        let one = log.push("one".to_string());
+        log.create_branch("experiment").unwrap();
+        assert_eq!(Err(LogError::BranchExists("experiment".to_string())),
+                   log.create_branch("experiment"));
+        assert_eq!(true, log.create_branch("../escape").is_err());
+        let two = log.push("two".to_string());
+
+        log.switch_branch("experiment").unwrap();
+        assert_eq!("experiment", log.branch());
+        assert_eq!(Some(one), log.head_hash());
+        let three = log.push("three".to_string());
+        assert_eq!(vec!["experiment".to_string(), "main".to_string()],
+                   log.list_branches().unwrap());
+
+        // The main history and the head reference are untouched
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        assert_eq!("main", log.branch());
+        assert_eq!(Some(two), log.head_hash());
+        assert_eq!(Ok(Some(three)), log.branch_head("experiment"));
+        assert_eq!(Err(LogError::BranchNotFound("other".to_string())),
+                   log.switch_branch("other"));
+
+        // Branches are roots for the garbage collection
+        let report = GarbageCollection::new().without_backups().dry_run()
+            .collect::<String>(&log.hashio).unwrap();
+        assert_eq!(0, report.unreachable.len());
+
+        assert_eq!(true, log.delete_branch("main").is_err());
+        log.delete_branch("experiment").unwrap();
+        assert_eq!(vec!["main".to_string()], log.list_branches().unwrap());
+        log.switch_branch("main").unwrap();
+        assert_eq!(Some(two), log.head_hash());
+    }
+
+    #[test]
+    fn shallow_test() {
+        let store = MemoryStore::new();
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        log.push("one".to_string());
+        log.push("two".to_string());
+        let three = log.push("three".to_string());
+        log.push("four".to_string());
+        let five = log.push("five".to_string());
+
+        // The entries behind the anchor are still in the store
+        assert_eq!(vec![three], log.truncate(2).unwrap());
+        assert_eq!(2, log.merkle_tree().len());
+        assert_eq!(Ok(vec![three]), log.unshallow());
+        assert_eq!(0, read_anchors(&store).unwrap().len());
+        assert_eq!(5, log.merkle_tree().len());
+
+        assert_eq!(vec![three], log.truncate(2).unwrap());
+        assert_eq!(true, log.is_anchor(three));
+        assert_eq!(2, log.merkle_tree().len());
+        let entries: Vec<String> = LogIteratorRef::from_log(&log).collect();
+        assert_eq!(vec!["five".to_string(), "four".to_string()], entries);
+        assert_eq!(None, verify_log(&log));
+
+        // The older entries are not reachable anymore without the backups
+        let report = GarbageCollection::new().without_backups()
+            .collect::<String>(&log.hashio).unwrap();
+        assert_eq!(6, report.unreachable.len());
+        assert_eq!(4, store.list().unwrap().len());
+
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        assert_eq!(Some(five), log.head_hash());
+        assert_eq!(None, verify_log(&log));
+        assert_eq!(Ok(true), log.is_ancestor(three, five));
+        log.push("six".to_string());
+        assert_eq!(3, LogIteratorRef::from_log(&log).count());
+        assert_eq!(None, verify_log(&log));
+
+        // The history behind the anchor is gone
+        assert_eq!(Err(LogError::EntryNotFound(three)), log.remove_anchor(three));
+        assert_eq!(true, log.is_anchor(three));
+        assert_eq!(Ok(Vec::new()), log.unshallow());
+    }
+
+    #[test]
+    fn truncate_branch_test() {
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(MemoryStore::new())));
+        log.push("one".to_string());
+        log.create_branch("old").unwrap();
+        log.push("two".to_string());
+        log.push("three".to_string());
+
+        // The anchor would cut off the history of the other branch
+        assert_eq!(Err(LogError::InvalidBranch(
+                       "Branch old forks off behind the new anchors".to_string())),
+                   log.truncate(1));
+        assert_eq!(0, log.anchor_hashes().len());
+        log.delete_branch("old").unwrap();
+        assert_eq!(1, log.truncate(1).unwrap().len());
+
+        // A branch of the kept entries is fine
+        log.push("four".to_string());
+        log.create_branch("new").unwrap();
+        let five = log.push("five".to_string());
+        assert_eq!(1, log.truncate(2).unwrap().len());
+        assert_eq!(Some(five), log.head_hash());
+    }
+
+    #[test]
+    fn merge_test() {
+        let store = MemoryStore::new();
+        let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        let one = log.push("one".to_string());
+        let two = log.push("two".to_string());
+        log.reset_head(&one).unwrap();
+        let three = log.push("three".to_string());
+        assert_eq!(Ok(Some(one)), log.common_ancestor(two, three));
+        assert_eq!(Ok(vec![three]), log.history_since(Some(two), three));
+
+        let merged = log.try_merge(&[two], "merged".to_string()).unwrap();
+        assert_eq!(Ok(Some(three)), log.parent_hash(merged));
+        assert_eq!(Ok(true), log.is_ancestor(two, merged));
+        assert_eq!(Ok(Some(two)), log.common_ancestor(merged, two));
+        assert_eq!(Ok(vec![one, three, two, merged]), log.history_since(None, merged));
+        assert_eq!(None, verify_log(&log));
+
+        // Any number of histories can be merged at once
+        let four = log.try_push_with_parents("four".to_string(), vec![]).unwrap();
+        log.reset_head(&merged).unwrap();
+        let octopus = log.try_merge(&[one, four], "octopus".to_string()).unwrap();
+        assert_eq!(Ok(vec![merged, one, four]), log.parent_hashes(octopus));
+        assert_eq!(Ok(None), log.common_ancestor(three, four));
+
+        // The merged history survives without any reference to it
+        let log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        assert_eq!(6, LogIteratorDagHash::from_log(&log).count());
+        let report = GarbageCollection::new().without_backups().dry_run()
+            .collect::<String>(&log.hashio).unwrap();
+        assert_eq!(0, report.unreachable.len());
+        assert_eq!(Ok("two".to_string()), log.get(two));
+
+        // Every parent link is verified
+        store.delete(&four).unwrap();
+        assert_eq!(Some(LogVerifyFailure::LogError(LogError::EntryNotFound(four))),
+                   verify_log(&log));
+    }
 }
@@ -5,8 +5,18 @@ pub mod hash;
 pub mod io;
 #[macro_use]
 pub mod hashio;
+pub mod store;
+pub mod cache;
+pub mod pack;
+pub mod gc;
+pub mod fsck;
 pub mod log;
+pub mod merkle;
+pub mod sign;
+pub mod checkpoint;
 pub mod iolog;
+pub mod sync;
+pub mod bundle;
 pub mod task;
 
 pub mod tasklog;
@@ -45,14 +45,32 @@
 //! Hashable trait must be implemented for the types.  It's also
 //! possible to implement the Writable trait which provides a
 //! a helper function to calculate the hash.
+//!
+//! ## Merges
+//! An entry can have more than one parent, so the history of a log is a
+//! directed acyclic graph.  The first parent is the one returned by
+//! parent_hash and the simple iterators only follow it.  The DAG
+//! iterators and verify_log visit all parents.
+//!
+//! ## Shallow histories
+//! Old entries can be cut off a log.  The parents where the history was
+//! cut are recorded as anchors.  Their entries are not available anymore
+//! but they are trusted like the empty parent of a first entry, so the
+//! iterators, verify_log and rebuild_log stop there.
 
 
 extern crate time;
 
 use hash::*;
-use std::collections::BTreeMap;
+use hashio::*;
+use merkle::*;
+use sign::*;
+use std::cell::{Ref, RefCell};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::error::Error;
 use std::fmt;
+use std::io;
+use std::mem;
 
 
 // ---- Core types ----
@@ -62,7 +80,49 @@ pub trait Log {
     type Item: Hashable;
 
     /// Add new entry to the log
-    fn push(&mut self, Self::Item) -> Hash;
+    ///
+    /// Returns Hash::None if the entry could not be added.  Use try_push
+    /// to get the reason.
+    fn push(&mut self, item: Self::Item) -> Hash {
+        self.try_push(item).unwrap_or(Hash::None)
+    }
+
+    /// Add new entry to the log
+    ///
+    /// # Errors
+    /// Throws an error if the entry could not be stored.  The head is not
+    /// changed in this case.
+    fn try_push(&mut self, item: Self::Item) -> Result<Hash, LogError>;
+
+    /// Add new entry with the given parents and make it the head.
+    ///
+    /// The first parent is the one returned by parent_hash.  Without
+    /// parents, the entry starts a new history.
+    ///
+    /// # Errors
+    /// Throws an error if a parent doesn't exist or if the entry could not
+    /// be stored.  The head is not changed in this case.
+    fn try_push_with_parents(&mut self, item: Self::Item, parents: Vec<Hash>)
+        -> Result<Hash, LogError>;
+
+    /// Add an entry which merges other histories into the head.
+    ///
+    /// The head becomes the first parent of the entry, followed by the
+    /// merged hashes.  Combining the content of the histories is up to the
+    /// caller, the item should contain the result.
+    ///
+    /// # Errors
+    /// Throws an error if the log is empty or a merged hash doesn't exist.
+    fn try_merge(&mut self, merged: &[Hash], item: Self::Item) -> Result<Hash, LogError> {
+        match self.head_hash() {
+            None => Err(LogError::EntryNotFound(Hash::None)),
+            Some(head) => {
+                let mut parents = vec![head];
+                parents.extend_from_slice(merged);
+                self.try_push_with_parents(item, parents)
+            }
+        }
+    }
 
 
     /// Head hash
@@ -77,6 +137,14 @@ pub trait Log {
     /// Throws an error if an entry of the hash was not found.
     fn parent_hash(&self, hash: Hash) -> Result<Option<Hash>, LogError>;
 
+    /// Get all parent hashes of the given hash, the first parent first.
+    ///
+    /// # Errors
+    /// Throws an error if an entry of the hash was not found.
+    fn parent_hashes(&self, hash: Hash) -> Result<Vec<Hash>, LogError> {
+        Ok(try!(self.parent_hash(hash)).into_iter().collect())
+    }
+
     /// Get the borrowed entry of the given hash
     ///
     /// # Errors
@@ -93,6 +161,189 @@ pub trait Log {
 
     /// Reset head of log
     fn reset_head(&mut self, hash: &Hash) -> Result<(), LogError>;
+
+    /// Hashes where the history was cut off.
+    fn anchor_hashes(&self) -> Vec<Hash> {
+        Vec::new()
+    }
+
+    /// True if the history was cut off at the hash.
+    fn is_anchor(&self, hash: Hash) -> bool {
+        self.anchor_hashes().contains(&hash)
+    }
+
+    /// Trust the hash as the end of the history without its entry.
+    ///
+    /// # Errors
+    /// Throws an error if the anchor could not be stored.
+    fn add_anchor(&mut self, hash: Hash) -> Result<(), LogError>;
+
+    /// Continue the history behind the anchor again.
+    ///
+    /// The entry of the anchor and its history must be available, for
+    /// example because they were synced from a complete log.
+    ///
+    /// # Errors
+    /// Throws an error if an entry of the history behind the anchor was
+    /// not found or if the anchor could not be removed.  The anchor is
+    /// kept in these cases.
+    fn remove_anchor(&mut self, hash: Hash) -> Result<(), LogError>;
+
+    /// Calculate the hash the entry must have by its content.
+    ///
+    /// By default, the hash of the entry is combined with each parent hash
+    /// like DefaultLog does it.  The algorithm of the given hash is used.
+    ///
+    /// # Errors
+    /// Throws an error if an entry of the hash was not found.
+    fn expected_hash(&self, hash: Hash) -> Result<Hash, LogError> {
+        let entry = try!(self.get(hash));
+        let algorithm = hash.algorithm().unwrap_or_default();
+        Ok(chain_hash(entry.as_hash_with(algorithm), &try!(self.parent_hashes(hash))))
+    }
+
+    /// Signature of the entry.
+    ///
+    /// Returns the hash which was signed together with the signature or
+    /// None if the entry is not signed.
+    fn signature(&self, _: Hash) -> Result<Option<(Hash, EntrySignature)>, LogError> {
+        Ok(None)
+    }
+
+    /// True if the ancestor is reachable from the hash or equal to it.
+    ///
+    /// Anchors are part of the history but their parents are unknown.
+    ///
+    /// # Errors
+    /// Throws an error if an entry of the history was not found.
+    fn is_ancestor(&self, ancestor: Hash, hash: Hash) -> Result<bool, LogError> {
+        Ok(try!(ancestors(self, hash)).contains(&ancestor))
+    }
+
+    /// Find the latest entry both hashes have in their history.
+    ///
+    /// The history of the second hash is searched breadth first, so the
+    /// closest common entry is returned.  Returns None if the histories
+    /// are unrelated.
+    fn common_ancestor(&self, a: Hash, b: Hash) -> Result<Option<Hash>, LogError> {
+        let ancestors = try!(ancestors(self, a));
+        let mut visited = BTreeSet::new();
+        let mut pending = VecDeque::new();
+        pending.push_back(b);
+        while let Some(hash) = pending.pop_front() {
+            if ancestors.contains(&hash) {
+                return Ok(Some(hash));
+            }
+            if visited.insert(hash) && !self.is_anchor(hash) {
+                pending.extend(try!(self.parent_hashes(hash)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// All entries of the head which are not in the history of base.
+    ///
+    /// Parents come before their childs and the first parent is visited
+    /// before the others.  Without base, the whole history is returned.
+    /// Anchors are not part of the result.
+    ///
+    /// # Errors
+    /// Throws an error if an entry of the history was not found.
+    fn history_since(&self, base: Option<Hash>, head: Hash) -> Result<Vec<Hash>, LogError> {
+        let excluded = match base {
+            None => BTreeSet::new(),
+            Some(base) => try!(ancestors(self, base))
+        };
+        let mut result = Vec::new();
+        let mut visited = BTreeSet::new();
+        let mut pending = vec![(head, false)];
+        while let Some((hash, parents_done)) = pending.pop() {
+            if parents_done {
+                result.push(hash);
+                continue;
+            }
+            if excluded.contains(&hash) || self.is_anchor(hash) || !visited.insert(hash) {
+                continue;
+            }
+            pending.push((hash, true));
+            for parent in try!(self.parent_hashes(hash)).into_iter().rev() {
+                pending.push((parent, false));
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Combine the hash of an entry with the hashes of its parents.
+fn chain_hash(entry_hash: Hash, parents: &[Hash]) -> Hash {
+    match parents.split_first() {
+        None => entry_hash.as_hash_with(entry_hash.algorithm().unwrap_or_default()),
+        Some((first, others)) => others.iter()
+            .fold(entry_hash.hash_with(*first), |hash, parent| hash.hash_with(*parent))
+    }
+}
+
+/// The hash and all entries reachable from it by any parent.
+fn ancestors<L: Log + ?Sized>(log: &L, hash: Hash) -> Result<BTreeSet<Hash>, LogError> {
+    let mut result = BTreeSet::new();
+    let mut pending = vec![hash];
+    while let Some(hash) = pending.pop() {
+        if result.insert(hash) && !log.is_anchor(hash) {
+            pending.extend(try!(log.parent_hashes(hash)));
+        }
+    }
+    Ok(result)
+}
+
+/// First parent of the hash unless the history ends there.
+fn first_parent<L: Log + ?Sized>(log: &L, hash: Hash) -> Option<Hash> {
+    log.parent_hash(hash).unwrap_or(None).filter(|parent| !log.is_anchor(*parent))
+}
+
+/// Anchors which cut the history of the head down to the newest entries.
+///
+/// The last keep entries of history_since are kept, at least the head.
+/// Each of their parents which is not kept becomes an anchor.  Returns no
+/// anchors if the history is short enough already.
+///
+/// # Examples
+/// ```
+/// use tbd::log::*;
+/// let mut log = DefaultLog::<String>::default();
+///
+/// let one = log.push("one".to_string());
+/// log.push("two".to_string());
+/// log.push("three".to_string());
+/// let head = log.head_hash().unwrap();
+/// let anchors = shallow_anchors(&log, head, 2).unwrap();
+/// assert_eq!(vec![one], anchors);
+///
+/// log.add_anchor(one).unwrap();
+/// let entries: Vec<String> = log.iter().collect();
+/// assert_eq!(vec!["three", "two"], entries);
+/// assert_eq!(None, verify_log(&log));
+///
+/// // The rebuilt log still ends at the anchor
+/// let rebuilt: DefaultLog<String> = rebuild_log(&log).unwrap();
+/// assert_eq!(log.head_hash(), rebuilt.head_hash());
+/// ```
+pub fn shallow_anchors<L: Log + ?Sized>(log: &L, head: Hash, keep: usize)
+        -> Result<Vec<Hash>, LogError> {
+    let hashes = try!(log.history_since(None, head));
+    let keep = keep.max(1);
+    if hashes.len() <= keep {
+        return Ok(Vec::new());
+    }
+    let kept: BTreeSet<Hash> = hashes[hashes.len() - keep..].iter().cloned().collect();
+    let mut anchors = Vec::new();
+    for hash in hashes[hashes.len() - keep..].iter() {
+        for parent in try!(log.parent_hashes(*hash)) {
+            if !kept.contains(&parent) && !anchors.contains(&parent) {
+                anchors.push(parent);
+            }
+        }
+    }
+    Ok(anchors)
 }
 
 
@@ -140,7 +391,7 @@ impl<'a, L: Log<Item=T>, T: Hashable + 'a> Iterator for LogIteratorRef<'a, L, T>
             None => None,
             Some(hash) => {
                 let value = self.log.get(hash).ok();
-                self.hash = self.log.parent_hash(hash).unwrap_or(None);
+                self.hash = first_parent(self.log, hash);
                 value
             }
         }
@@ -183,6 +434,14 @@ impl<'a, L: Log<Item=T>, T: Hashable + 'a> LogIteratorHash<'a, L, T> {
             hash: log.head_hash()
         }
     }
+
+    /// Returns an iterator which starts at the given hash instead of the head.
+    pub fn from_hash(log: &'a L, hash: Hash) -> LogIteratorHash<'a, L, T> {
+        LogIteratorHash {
+            log: log,
+            hash: Some(hash)
+        }
+    }
 }
 
 impl<'a, L: Log<Item=T>, T: Hashable + 'a> Iterator for LogIteratorHash<'a, L, T> {
@@ -193,7 +452,7 @@ impl<'a, L: Log<Item=T>, T: Hashable + 'a> Iterator for LogIteratorHash<'a, L, T
             None => None,
             Some(hash) => {
                 let value = self.hash;
-                self.hash = self.log.parent_hash(hash).unwrap_or(None);
+                self.hash = first_parent(self.log, hash);
                 value
             }
         }
@@ -201,23 +460,180 @@ impl<'a, L: Log<Item=T>, T: Hashable + 'a> Iterator for LogIteratorHash<'a, L, T
 }
 
 
+/// Iterator over the hashes of all entries including merged histories.
+///
+/// Each entry comes before all of its parents, so the head is first.  The
+/// order is determined when the iterator is created.  If the history can't
+/// be read completely, the iterator is empty; use history_since to get
+/// the error.
+///
+/// ```
+/// use tbd::log::*;
+/// let mut log = DefaultLog::<String>::default();
+///
+/// let one = log.push("one".to_string());
+/// let two = log.push("two".to_string());
+/// log.reset_head(&one).unwrap();
+/// let three = log.push("three".to_string());
+/// let merged = log.try_merge(&[two], "merged".to_string()).unwrap();
+///
+/// let hashes: Vec<_> = LogIteratorDagHash::from_log(&log).collect();
+/// assert_eq!(vec![merged, two, three, one], hashes);
+/// // The simple iterator only follows the first parent
+/// assert_eq!(3, log.hash_iter().count());
+/// ```
+pub struct LogIteratorDagHash<'a, L: Log<Item=T> + 'a, T: Hashable> {
+    log: &'a L,
+    hashes: Vec<Hash>
+}
+
+impl<'a, L: Log<Item=T>, T: Hashable + 'a> LogIteratorDagHash<'a, L, T> {
+    /// Returns an iterator over the whole history of the log.
+    pub fn from_log(log: &'a L) -> LogIteratorDagHash<'a, L, T> {
+        let hashes = match log.head_hash() {
+            None => Vec::new(),
+            Some(head) => log.history_since(None, head).unwrap_or(Vec::new())
+        };
+        LogIteratorDagHash {
+            log: log,
+            hashes: hashes
+        }
+    }
+}
+
+impl<'a, L: Log<Item=T>, T: Hashable + 'a> Iterator for LogIteratorDagHash<'a, L, T> {
+    type Item = Hash;
+
+    fn next(&mut self) -> Option<Hash> {
+        self.hashes.pop()
+    }
+}
+
+/// Iterator over all entries including merged histories.
+///
+/// See LogIteratorDagHash for the order.
+pub struct LogIteratorDagRef<'a, L: Log<Item=T> + 'a, T: Hashable> {
+    hashes: LogIteratorDagHash<'a, L, T>
+}
+
+impl<'a, L: Log<Item=T>, T: Hashable + 'a> LogIteratorDagRef<'a, L, T> {
+    /// Returns an iterator over the whole history of the log.
+    pub fn from_log(log: &'a L) -> LogIteratorDagRef<'a, L, T> {
+        LogIteratorDagRef {
+            hashes: LogIteratorDagHash::from_log(log)
+        }
+    }
+}
+
+impl<'a, L: Log<Item=T>, T: Hashable + 'a> Iterator for LogIteratorDagRef<'a, L, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self.hashes.next() {
+            None => None,
+            Some(hash) => self.hashes.log.get(hash).ok()
+        }
+    }
+}
+
+
 
 
 
 
 // ---- DefaultLogEntry implementations ----
 /// Error type for the default log.
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub enum LogError {
+    /// There is no entry for the hash.
     EntryNotFound(Hash),
+
+    /// The storage failed while the entry for the hash was read or written.
+    ///
+    /// Contains the original error which tells if it was an IO error or
+    /// if the stored data could not be parsed.
+    StorageError(Hash, HashIOError),
+
+    /// The entry stored for a hash calculates to another hash.
+    CorruptedEntry {
+        hash: Hash,
+        actual_hash: Hash
+    },
+
+    /// The entry is not an ancestor of the head.
+    NotAncestor {
+        hash: Hash,
+        head: Hash
+    },
+
+    /// The head doesn't match the latest trusted checkpoint.
+    InvalidCheckpoint(String),
+
+    /// There is no branch with the name.
+    BranchNotFound(String),
+
+    /// A branch with the name exists already.
+    BranchExists(String),
+
+    /// The branch operation is not allowed, the message tells why.
+    InvalidBranch(String),
     Unknown
 }
 
+impl LogError {
+    /// Convert a HashIOError which occured while the hash was resolved.
+    ///
+    /// A missing object is reported as EntryNotFound.
+    pub fn from_hashio_error(hash: Hash, err: HashIOError) -> LogError {
+        match err {
+            HashIOError::IOError(ref io_err) if io_err.kind() == io::ErrorKind::NotFound =>
+                LogError::EntryNotFound(hash),
+            err => LogError::StorageError(hash, err)
+        }
+    }
+}
+
+impl PartialEq for LogError {
+    /// Storage errors are equal if their hash and message are equal.
+    fn eq(&self, other: &LogError) -> bool {
+        match (self, other) {
+            (&LogError::EntryNotFound(ref a), &LogError::EntryNotFound(ref b)) => a == b,
+            (&LogError::StorageError(ref a, ref a_err),
+             &LogError::StorageError(ref b, ref b_err)) =>
+                a == b && format!("{}", a_err) == format!("{}", b_err),
+            (&LogError::CorruptedEntry { hash: ref a, actual_hash: ref a_actual },
+             &LogError::CorruptedEntry { hash: ref b, actual_hash: ref b_actual }) =>
+                a == b && a_actual == b_actual,
+            (&LogError::NotAncestor { hash: ref a, head: ref a_head },
+             &LogError::NotAncestor { hash: ref b, head: ref b_head }) =>
+                a == b && a_head == b_head,
+            (&LogError::InvalidCheckpoint(ref a), &LogError::InvalidCheckpoint(ref b)) => a == b,
+            (&LogError::BranchNotFound(ref a), &LogError::BranchNotFound(ref b)) => a == b,
+            (&LogError::BranchExists(ref a), &LogError::BranchExists(ref b)) => a == b,
+            (&LogError::InvalidBranch(ref a), &LogError::InvalidBranch(ref b)) => a == b,
+            (&LogError::Unknown, &LogError::Unknown) => true,
+            _ => false
+        }
+    }
+}
+
 impl fmt::Display for LogError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             LogError::EntryNotFound(hash) => write!(f, "Entry not found for hash: {}",
                                           hash.as_string()),
+            LogError::StorageError(hash, ref err) => write!(f, "Storage error for hash {}: {}",
+                                          hash.as_string(), err),
+            LogError::CorruptedEntry { hash, actual_hash } =>
+                write!(f, "Entry for hash {} is corrupted, it has the hash {}",
+                       hash.as_string(), actual_hash.as_string()),
+            LogError::NotAncestor { hash, head } =>
+                write!(f, "Entry {} is not an ancestor of {}",
+                       hash.as_string(), head.as_string()),
+            LogError::InvalidCheckpoint(ref msg) => write!(f, "Invalid checkpoint: {}", msg),
+            LogError::BranchNotFound(ref name) => write!(f, "Branch not found: {}", name),
+            LogError::BranchExists(ref name) => write!(f, "Branch exists already: {}", name),
+            LogError::InvalidBranch(ref msg) => write!(f, "Invalid branch operation: {}", msg),
             LogError::Unknown => write!(f, "Unknown log error")
         }
     }
@@ -227,19 +643,40 @@ impl Error for LogError {
     fn description(&self) -> &str {
         match *self {
             LogError::EntryNotFound(_) => "Entry for hash not found",
+            LogError::StorageError(_, _) => "Storage error",
+            LogError::CorruptedEntry { .. } => "Entry is corrupted",
+            LogError::NotAncestor { .. } => "Entry is not an ancestor",
+            LogError::InvalidCheckpoint(_) => "Invalid checkpoint",
+            LogError::BranchNotFound(_) => "Branch not found",
+            LogError::BranchExists(_) => "Branch exists already",
+            LogError::InvalidBranch(_) => "Invalid branch operation",
             LogError::Unknown => "Unknown log error"
         }
     }
+
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            LogError::StorageError(_, ref err) => Some(err),
+            _ => None
+        }
+    }
 }
 
 
 /// Type for each entry of the DefaultLog.
+#[derive(Clone)]
 pub struct DefaultLogEntry<T: Hashable + Clone> {
     /// Holds the actial entry.
     pub entry: T,
 
     /// Reference to the parent.
-    pub parent_hash: Option<Hash>
+    pub parent_hash: Option<Hash>,
+
+    /// Further parents if the entry is a merge.
+    pub merge_hashes: Vec<Hash>,
+
+    /// Signature of the entry hash if the log has a signing key.
+    pub signature: Option<EntrySignature>
 }
 
 /// Default implmentation of the Log.
@@ -247,10 +684,14 @@ pub struct DefaultLogEntry<T: Hashable + Clone> {
 /// It already provides functions to generate iterators for its entries and
 /// hashes.
 pub struct DefaultLog<T: Hashable + Clone> {
-    entries: BTreeMap<Hash, DefaultLogEntry<T>>,
+    entries: RefCell<BTreeMap<Hash, DefaultLogEntry<T>>>,
     head: Option<Hash>,
+    algorithm: HashAlgorithm,
+    merkle: RefCell<MerkleTree>,
+    signing_key: Option<SigningKey>,
+    anchors: BTreeSet<Hash>,
     load: Box<Fn(Hash) -> Option<DefaultLogEntry<T>>>,
-    save: Box<Fn(&DefaultLogEntry<T>)>
+    save: Box<Fn(Hash, &DefaultLogEntry<T>) -> Result<(), LogError>>
 }
 
 impl<T: Hashable + Clone> DefaultLog<T> {
@@ -264,20 +705,101 @@ impl<T: Hashable + Clone> DefaultLog<T> {
         LogIteratorHash::from_log(self)
     }
 
+    /// Get an iterator for the entries of all merged histories.
+    pub fn dag_iter(&self) -> LogIteratorDagRef<DefaultLog<T>, T> {
+        LogIteratorDagRef::from_log(self)
+    }
+
+    /// Use the given algorithm to hash new entries.
+    pub fn with_algorithm(mut self, algorithm: HashAlgorithm) -> DefaultLog<T> {
+        self.algorithm = algorithm;
+        self.merkle = RefCell::new(MerkleTree::new(algorithm));
+        self
+    }
+
+    /// Sign every new entry with the key.
+    pub fn with_signing_key(mut self, key: SigningKey) -> DefaultLog<T> {
+        self.signing_key = Some(key);
+        self
+    }
+
     /// Set load function called when an entry was not found.
+    ///
+    /// A loaded entry is kept in memory, so the function is called at most
+    /// once per hash.
     pub fn with_load_fn(mut self, load_fn: Box<Fn(Hash) -> Option<DefaultLogEntry<T>>>) -> DefaultLog<T> {
         self.load = load_fn;
         self
     }
 
-    /// Set load function when entries should be saved.
+    /// Set save function which is called for every pushed entry.
+    ///
+    /// Together with the load function, the log can be used as write
+    /// through cache for any persistence layer.  If the function returns
+    /// an error, try_push returns it and the log stays unchanged.
     ///
-    /// This is not used yet.
-    pub fn with_save_fn(mut self, save_fn: Box<Fn(&DefaultLogEntry<T>)>) -> DefaultLog<T> {
+    /// # Examples
+    /// ```
+    /// use tbd::log::*;
+    /// use tbd::merkle::*;
+    /// use std::cell::RefCell;
+    /// use std::collections::BTreeMap;
+    /// use std::rc::Rc;
+    ///
+    /// let storage = Rc::new(RefCell::new(BTreeMap::new()));
+    /// let save_storage = storage.clone();
+    /// let mut log = DefaultLog::<String>::default()
+    ///     .with_save_fn(Box::new(move |hash, entry| {
+    ///         save_storage.borrow_mut().insert(hash, entry.clone());
+    ///         Ok(())
+    ///     }));
+    /// log.push("one".to_string());
+    /// let head = log.push("two".to_string());
+    ///
+    /// // Another log loads the entries on demand
+    /// let load_storage = storage.clone();
+    /// let mut log = DefaultLog::<String>::default()
+    ///     .with_load_fn(Box::new(move |hash| load_storage.borrow().get(&hash).cloned()));
+    /// log.reset_head(&head).unwrap();
+    /// let entries: Vec<String> = log.iter().collect();
+    /// assert_eq!(vec!["two".to_string(), "one".to_string()], entries);
+    ///
+    /// // A failing storage rejects the entry
+    /// let mut log = DefaultLog::<String>::default()
+    ///     .with_save_fn(Box::new(|hash, _| Err(LogError::EntryNotFound(hash))));
+    /// assert_eq!(true, log.try_push("one".to_string()).is_err());
+    /// assert_eq!(None, log.head_hash());
+    /// assert_eq!(0, log.merkle_tree().len());
+    /// ```
+    pub fn with_save_fn(mut self, save_fn: Box<Fn(Hash, &DefaultLogEntry<T>) -> Result<(), LogError>>)
+            -> DefaultLog<T> {
         self.save = save_fn;
         self
     }
 
+    /// Call f with the entry of the hash.
+    ///
+    /// If the entry is not in memory, the load function is asked for it.
+    fn with_entry<R, F>(&self, hash: Hash, f: F) -> Result<R, LogError>
+            where F: FnOnce(&DefaultLogEntry<T>) -> R {
+        if !self.entries.borrow().contains_key(&hash) {
+            match (self.load)(hash) {
+                Some(entry) => {
+                    self.entries.borrow_mut().insert(hash, entry);
+                },
+                None => return Err(LogError::EntryNotFound(hash))
+            }
+        }
+        Ok(f(&self.entries.borrow()[&hash]))
+    }
+
+    /// Update the Merkle tree to the current head.
+    fn follow_merkle_head(&mut self) -> Result<(), LogError> {
+        let mut merkle = mem::replace(self.merkle.get_mut(), MerkleTree::new(self.algorithm));
+        let result = merkle.follow_head(&*self, self.head);
+        *self.merkle.get_mut() = merkle;
+        result
+    }
 }
 
 impl<T: Hashable + Clone> Log for DefaultLog<T> {
@@ -287,19 +809,39 @@ impl<T: Hashable + Clone> Log for DefaultLog<T> {
     /// Add new entry to log.
     ///
     /// Returns the hash value for the entry.
-    fn push(&mut self, t: T) -> Hash {
-        let entry_hash = t.as_hash();
-        let hash = match self.head {
-            None => entry_hash.as_hash(),
-            Some(head_hash) => entry_hash.hash_with(head_hash)
-        };
+    ///
+    /// # Errors
+    /// Throws the error of the save function, usually a StorageError, if
+    /// the entry could not be stored.  The head stays unchanged in this
+    /// case.
+    fn try_push(&mut self, t: T) -> Result<Hash, LogError> {
+        let parents = self.head.into_iter().collect();
+        self.try_push_with_parents(t, parents)
+    }
+
+    fn try_push_with_parents(&mut self, t: T, parents: Vec<Hash>) -> Result<Hash, LogError> {
+        for parent in parents.iter() {
+            if !self.anchors.contains(parent) {
+                try!(self.with_entry(*parent, |_| ()));
+            }
+        }
+        let hash = chain_hash(t.as_hash_with(self.algorithm), &parents);
+        let extends_head = parents.first() == self.head.as_ref();
         let log_entry = DefaultLogEntry {
             entry: t,
-            parent_hash: self.head
+            parent_hash: parents.first().cloned(),
+            merge_hashes: parents.into_iter().skip(1).collect(),
+            signature: self.signing_key.as_ref().map(|key| key.sign(&hash))
         };
-        self.entries.insert(hash, log_entry);
+        try!((self.save)(hash, &log_entry));
+        self.entries.borrow_mut().insert(hash, log_entry);
         self.head = Some(hash);
-        hash
+        if extends_head {
+            self.merkle.get_mut().push(hash);
+        } else {
+            try!(self.follow_merkle_head());
+        }
+        Ok(hash)
     }
 
     /// Get the hash of the newest entry if not empty.
@@ -313,38 +855,77 @@ impl<T: Hashable + Clone> Log for DefaultLog<T> {
     ///
     /// Returns None if parameter hash was not found or if it was empty.
     fn parent_hash(&self, hash: Hash) -> Result<Option<Hash>, LogError> {
-        match self.entries.get(&hash) {
-            None => Result::Err(LogError::EntryNotFound(hash)),
-            Some(ref entry) => Ok(entry.parent_hash)
-        }
+        self.with_entry(hash, |entry| entry.parent_hash)
+    }
+
+    fn parent_hashes(&self, hash: Hash) -> Result<Vec<Hash>, LogError> {
+        self.with_entry(hash, |entry| {
+            entry.parent_hash.iter().chain(entry.merge_hashes.iter()).cloned().collect()
+        })
     }
 
     /// Get entry with 
     fn get(&self, hash: Hash) -> Result<Self::Item, LogError> {
-        match self.entries.get(&hash) {
-            None => Result::Err(LogError::EntryNotFound(hash)),
-            Some(ref entry) => Ok(entry.entry.clone())
-        }
+        self.with_entry(hash, |entry| entry.entry.clone())
+    }
+
+    fn signature(&self, hash: Hash) -> Result<Option<(Hash, EntrySignature)>, LogError> {
+        self.with_entry(hash, |entry| entry.signature.map(|signature| (hash, signature)))
     }
 
     fn reset_head(&mut self, hash: &Hash) -> Result<(), LogError> {
-        match self.entries.get(&hash) {
-            None => Result::Err(LogError::EntryNotFound(hash.clone())),
-            Some(_) => {
-                self.head = Some(hash.clone());
-                Ok(())
-            }
+        try!(self.with_entry(*hash, |_| ()));
+        self.head = Some(hash.clone());
+        self.follow_merkle_head()
+    }
+
+    fn anchor_hashes(&self) -> Vec<Hash> {
+        self.anchors.iter().cloned().collect()
+    }
+
+    fn is_anchor(&self, hash: Hash) -> bool {
+        self.anchors.contains(&hash)
+    }
+
+    /// Entries behind the anchor are not dropped from memory.
+    fn add_anchor(&mut self, hash: Hash) -> Result<(), LogError> {
+        self.anchors.insert(hash);
+        self.merkle.get_mut().cut(&hash);
+        Ok(())
+    }
+
+    fn remove_anchor(&mut self, hash: Hash) -> Result<(), LogError> {
+        if !self.anchors.remove(&hash) {
+            return Ok(());
+        }
+        if let Err(err) = self.history_since(None, hash) {
+            self.anchors.insert(hash);
+            return Err(err);
         }
+        // The older entries are added in front of the tree
+        let merkle = try!(MerkleTree::from_log(&*self, self.algorithm));
+        *self.merkle.get_mut() = merkle;
+        Ok(())
+    }
+}
+
+impl<T: Hashable + Clone> MerkleLog for DefaultLog<T> {
+    fn merkle_tree(&self) -> Ref<MerkleTree> {
+        self.merkle.borrow()
     }
 }
 
 impl<T: Hashable + Clone> Default for DefaultLog<T> {
     fn default() -> Self {
         DefaultLog {
-            entries: BTreeMap::new(),
+            entries: RefCell::new(BTreeMap::new()),
             head: None,
+            algorithm: HashAlgorithm::default(),
+            merkle: RefCell::new(MerkleTree::new(HashAlgorithm::default())),
+            signing_key: None,
+            anchors: BTreeSet::new(),
             load: Box::new(|_| None),
-            save: Box::new(|_| ())
+            save: Box::new(|_, _| Ok(()))
         }
     }
 }
@@ -358,7 +939,19 @@ pub enum LogVerifyFailure<T> {
         actual_hash: Hash,
         expected_hash: Hash
     },
-    LogError(LogError)
+    LogError(LogError),
+
+    /// The entry has a signature which doesn't match.
+    InvalidSignature(Hash),
+
+    /// The entry is not signed although trusted keys were given.
+    UnsignedEntry(Hash),
+
+    /// The entry is signed by a key which is not trusted.
+    UntrustedSignature {
+        hash: Hash,
+        public_key: PublicKey
+    }
 }
 
 fn gen_verify_failure<T>(t: T, act: Hash, exp: Hash)
@@ -373,27 +966,68 @@ fn gen_verify_failure<T>(t: T, act: Hash, exp: Hash)
 
 /// Verifies if the hash values of all entries are correct.
 ///
+/// All parents of each entry are visited, so a missing parent is reported
+/// as LogError unless it's an anchor.  Each entry is checked with the
+/// algorithm of its own hash.
+/// Signatures are checked if available but they are not required.
 pub fn verify_log<L, T>(log: &L) -> Option<LogVerifyFailure<T>>
         where L: Log<Item=T>, T: Hashable {
-    let hashes: Vec<Hash> = LogIteratorHash::from_log(log).collect();
-    for hash in hashes.iter().rev() {
-        let parent_hash_result = log.parent_hash(*hash);
+    verify_entries(log, None)
+}
+
+/// Verifies the hashes and that all entries are signed by a trusted key.
+///
+/// An entry fails if its content doesn't match the hash which was signed,
+/// for example because the stored item of a signed IOLog entry was
+/// replaced.
+pub fn verify_log_with_keys<L, T>(log: &L, trusted_keys: &[PublicKey])
+            -> Option<LogVerifyFailure<T>>
+        where L: Log<Item=T>, T: Hashable {
+    verify_entries(log, Some(trusted_keys))
+}
+
+fn verify_entries<L, T>(log: &L, trusted_keys: Option<&[PublicKey]>)
+            -> Option<LogVerifyFailure<T>>
+        where L: Log<Item=T>, T: Hashable {
+    let hashes = match log.head_hash() {
+        None => Vec::new(),
+        Some(head) => match log.history_since(None, head) {
+            Ok(hashes) => hashes,
+            Err(err) => return Some(LogVerifyFailure::LogError(err))
+        }
+    };
+    for hash in hashes.iter() {
         let entry = match log.get(*hash) {
             Err(err) => return Some(LogVerifyFailure::LogError(err)),
             Ok(hash) => hash
         };
-        let entry_hash = entry.as_hash();
-        let expected_hash = match parent_hash_result {
-            Ok(parent_hash_option) => match parent_hash_option {
-                None => entry_hash.as_hash(),
-                Some(parent_hash) => entry_hash.hash_with(parent_hash)
-            },
+        let expected_hash = match log.expected_hash(*hash) {
+            Ok(expected_hash) => expected_hash,
             Err(err) => return Some(LogVerifyFailure::LogError(err))
         };
-
         if *hash != expected_hash {
             return Some(gen_verify_failure(entry, *hash, expected_hash));
         }
+
+        match log.signature(*hash) {
+            Err(err) => return Some(LogVerifyFailure::LogError(err)),
+            Ok(None) => if trusted_keys.is_some() {
+                return Some(LogVerifyFailure::UnsignedEntry(*hash));
+            },
+            Ok(Some((signed_hash, signature))) => {
+                if !signature.verify(&signed_hash) {
+                    return Some(LogVerifyFailure::InvalidSignature(*hash));
+                }
+                if let Some(keys) = trusted_keys {
+                    if !keys.contains(&signature.public_key) {
+                        return Some(LogVerifyFailure::UntrustedSignature {
+                            hash: *hash,
+                            public_key: signature.public_key
+                        });
+                    }
+                }
+            }
+        }
     }
     None
 }
@@ -403,15 +1037,47 @@ pub fn verify_log<L, T>(log: &L) -> Option<LogVerifyFailure<T>>
 ///
 /// Rebuilding a log will create a new log of the same type and insert all
 /// entries again.  This can be used to fix wrong hashes caused by maniputation.
+/// Merges are kept, each entry gets the new hashes of its parents.
+/// Anchors are copied and keep their hashes.
+///
+/// # Examples
+/// ```
+/// use tbd::log::*;
+/// let mut log = DefaultLog::<String>::default();
+///
+/// let one = log.push("one".to_string());
+/// let two = log.push("two".to_string());
+/// log.reset_head(&one).unwrap();
+/// log.push("three".to_string());
+/// log.try_merge(&[two], "merged".to_string()).unwrap();
+/// assert_eq!(None, verify_log(&log));
+///
+/// let rebuilt: DefaultLog<String> = rebuild_log(&log).unwrap();
+/// assert_eq!(None, verify_log(&rebuilt));
+/// assert_eq!(log.head_hash(), rebuilt.head_hash());
+/// let entries: Vec<String> = rebuilt.dag_iter().collect();
+/// assert_eq!(vec!["merged", "two", "three", "one"], entries);
+/// ```
 
 pub fn rebuild_log<L, T>(log: &L) -> Result<L, LogError>
                 where L: Log<Item=T> + Default,
                       T: Hashable + Clone {
     let mut res: L = Default::default();
-    let hashes: Vec<Hash> = LogIteratorHash::from_log(log).collect();
-    for hash in hashes.iter().rev() {
+    let hashes = match log.head_hash() {
+        None => return Ok(res),
+        Some(head) => try!(log.history_since(None, head))
+    };
+    for anchor in log.anchor_hashes() {
+        try!(res.add_anchor(anchor));
+    }
+    let mut new_hashes = BTreeMap::new();
+    for hash in hashes.iter() {
         let entry = try!(log.get(*hash));
-        res.push(entry.clone());
+        let parents = try!(log.parent_hashes(*hash)).iter()
+            .map(|parent| new_hashes.get(parent).cloned().unwrap_or(*parent))
+            .collect();
+        let new_hash = try!(res.try_push_with_parents(entry.clone(), parents));
+        new_hashes.insert(*hash, new_hash);
     }
     Ok(res)
 }
@@ -0,0 +1,681 @@
+//! Merkle tree over the entries of a log
+//!
+//! # Usage
+//! To trust an entry, verify_log has to walk the whole chain of a log.
+//! A MerkleTree is kept next to the log instead and contains the hashes
+//! of all entries from the oldest to the newest one.  Its root hash stands
+//! for the complete log, like the head hash does.
+//!
+//! For any entry, the log can create an InclusionProof which only
+//! contains O(log n) hashes.  Somebody who knows the root hash can check
+//! the proof with verify_inclusion without having access to the log.
+//!
+//! If a head and its root were published and later a newer head, a
+//! ConsistencyProof shows that the newer log only appended entries to the
+//! older one.  It's checked by verify_consistency with both roots.
+//!
+//! The tree follows the layout of RFC 6962 (Certificate Transparency):
+//! leaves are hashed with a 0 byte in front and inner nodes with a 1 byte,
+//! so a leaf can't be confused with a node.
+//!
+//! The roots of all complete subtrees are cached and updated on every
+//! push, so a root or a proof only needs O(log n) hashes.  If the head of
+//! a log moves, follow_head only walks the entries up to the first one
+//! which is already in the tree.
+
+use std::cell::Ref;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{Read, Write};
+use hash::*;
+use hashio::*;
+use io::*;
+use log::*;
+
+
+/// Hash of a single leaf.
+fn leaf_hash(algorithm: HashAlgorithm, leaf: &Hash) -> Hash {
+    let mut bytes = vec![0u8];
+    write_hash(leaf, &mut bytes).expect("Writing to a vec should not cause any issues");
+    algorithm.hash_bytes(&bytes)
+}
+
+/// Hash of an inner node.
+fn node_hash(algorithm: HashAlgorithm, left: &Hash, right: &Hash) -> Hash {
+    let mut bytes = vec![1u8];
+    bytes.extend_from_slice(&*left.get_bytes());
+    bytes.extend_from_slice(&*right.get_bytes());
+    algorithm.hash_bytes(&bytes)
+}
+
+/// Largest power of two which is smaller than n.
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+
+/// Merkle tree whose leaves are the entry hashes of a log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleTree {
+    algorithm: HashAlgorithm,
+    leaves: Vec<Hash>,
+    positions: BTreeMap<Hash, usize>,
+
+    /// Roots of the complete subtrees.
+    ///
+    /// `nodes[level][i]` covers the `2^level` leaves starting at
+    /// `i * 2^level`, level 0 contains the leaf hashes.
+    nodes: Vec<Vec<Hash>>
+}
+
+impl MerkleTree {
+    /// Create an empty tree which uses the given algorithm for its nodes.
+    pub fn new(algorithm: HashAlgorithm) -> MerkleTree {
+        MerkleTree {
+            algorithm: algorithm,
+            leaves: Vec::new(),
+            positions: BTreeMap::new(),
+            nodes: Vec::new()
+        }
+    }
+
+    /// Build the tree for all entries from the head of the log.
+    ///
+    /// # Errors
+    /// Fails if an entry of the log could not be read.
+    pub fn from_log<L: Log + ?Sized>(log: &L, algorithm: HashAlgorithm) -> Result<MerkleTree, LogError> {
+        MerkleTree::from_head(log, log.head_hash(), algorithm)
+    }
+
+    /// Build the tree for all entries from the given head.
+    ///
+    /// The first parents are followed up to the first entry or an anchor.
+    ///
+    /// # Errors
+    /// Fails if an entry of the log could not be read.
+    pub fn from_head<L: Log + ?Sized>(log: &L, head: Option<Hash>, algorithm: HashAlgorithm)
+            -> Result<MerkleTree, LogError> {
+        let mut tree = MerkleTree::new(algorithm);
+        try!(tree.follow_head(log, head));
+        Ok(tree)
+    }
+
+    /// Update the tree to the entries of another head of the log.
+    ///
+    /// The first parents of the head are only followed until an entry of
+    /// the tree is reached.  The newer leaves are dropped and the walked
+    /// entries are added instead.
+    ///
+    /// # Errors
+    /// Fails if an entry of the log could not be read.  The tree is not
+    /// changed in this case.
+    pub fn follow_head<L: Log + ?Sized>(&mut self, log: &L, head: Option<Hash>)
+            -> Result<(), LogError> {
+        let mut hashes = Vec::new();
+        let mut next = head;
+        let mut len = 0;
+        while let Some(hash) = next {
+            if let Some(index) = self.index_of(&hash) {
+                len = index + 1;
+                break;
+            }
+            hashes.push(hash);
+            next = try!(log.parent_hash(hash)).filter(|parent| !log.is_anchor(*parent));
+        }
+        self.truncate(len);
+        for hash in hashes.into_iter().rev() {
+            self.push(hash);
+        }
+        Ok(())
+    }
+
+    /// Drop the leaf and all older leaves.
+    ///
+    /// Used when the history of a log is cut at an anchor.  Nothing
+    /// happens if the hash is not a leaf.
+    pub fn cut(&mut self, anchor: &Hash) {
+        if let Some(index) = self.index_of(anchor) {
+            let leaves = self.leaves.split_off(index + 1);
+            *self = MerkleTree::new(self.algorithm);
+            for leaf in leaves {
+                self.push(leaf);
+            }
+        }
+    }
+
+    /// Algorithm which is used for the nodes.
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Add the hash of a new log entry.
+    ///
+    /// Every subtree which gets complete by the leaf is hashed.
+    pub fn push(&mut self, leaf: Hash) {
+        self.positions.insert(leaf, self.leaves.len());
+        self.leaves.push(leaf);
+        let mut node = leaf_hash(self.algorithm, &leaf);
+        let mut level = 0;
+        loop {
+            if self.nodes.len() == level {
+                self.nodes.push(Vec::new());
+            }
+            self.nodes[level].push(node);
+            let count = self.nodes[level].len();
+            if count % 2 == 1 {
+                break;
+            }
+            node = node_hash(self.algorithm, &self.nodes[level][count - 2],
+                             &self.nodes[level][count - 1]);
+            level += 1;
+        }
+    }
+
+    /// Only keep the first len leaves.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.leaves.len() {
+            return;
+        }
+        for leaf in self.leaves.drain(len..) {
+            self.positions.remove(&leaf);
+        }
+        for (level, nodes) in self.nodes.iter_mut().enumerate() {
+            nodes.truncate(len >> level);
+        }
+        while self.nodes.last().map(|nodes| nodes.is_empty()).unwrap_or(false) {
+            self.nodes.pop();
+        }
+    }
+
+    /// Number of leaves.
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// Position of the entry hash, the oldest entry has position 0.
+    pub fn index_of(&self, leaf: &Hash) -> Option<usize> {
+        self.positions.get(leaf).cloned()
+    }
+
+    /// Root hash of the complete tree.
+    pub fn root(&self) -> Hash {
+        self.subtree_root(0, self.leaves.len())
+    }
+
+    /// Root hash of the tree which only contained the first size leaves.
+    ///
+    /// Returns None if the tree has less leaves.
+    pub fn root_at(&self, size: usize) -> Option<Hash> {
+        if size > self.leaves.len() {
+            return None;
+        }
+        Some(self.subtree_root(0, size))
+    }
+
+    /// Root of the leaves from start to end (exclusive).
+    ///
+    /// Complete subtrees are taken from the cache.
+    fn subtree_root(&self, start: usize, end: usize) -> Hash {
+        match end - start {
+            0 => self.algorithm.hash_bytes(&[]),
+            n if n.is_power_of_two() && start % n == 0 => {
+                self.nodes[n.trailing_zeros() as usize][start / n]
+            },
+            n => {
+                let k = split_point(n);
+                node_hash(self.algorithm,
+                          &self.subtree_root(start, start + k),
+                          &self.subtree_root(start + k, end))
+            }
+        }
+    }
+
+    /// Create a proof that the entry hash is part of the tree.
+    ///
+    /// Returns None if the hash is not a leaf of the tree.
+    pub fn inclusion_proof(&self, leaf: &Hash) -> Option<InclusionProof> {
+        let index = match self.index_of(leaf) {
+            Some(index) => index,
+            None => return None
+        };
+        let mut path = Vec::new();
+        self.inclusion_path(index, 0, self.leaves.len(), &mut path);
+        Some(InclusionProof {
+            leaf: *leaf,
+            index: index as u64,
+            tree_size: self.leaves.len() as u64,
+            path: path
+        })
+    }
+
+    /// Hashes which prove that the tree of the first old_size leaves is a
+    /// prefix of this tree.
+    fn consistency_path(&self, old_size: usize) -> Vec<Hash> {
+        let mut path = Vec::new();
+        if old_size > 0 {
+            self.consistency_subpath(old_size, 0, self.leaves.len(), true, &mut path);
+        }
+        path
+    }
+
+    fn consistency_subpath(&self, m: usize, start: usize, end: usize, complete: bool,
+                           path: &mut Vec<Hash>) {
+        let n = end - start;
+        if m == n {
+            if !complete {
+                path.push(self.subtree_root(start, end));
+            }
+            return;
+        }
+        let k = split_point(n);
+        if m <= k {
+            self.consistency_subpath(m, start, start + k, complete, path);
+            path.push(self.subtree_root(start + k, end));
+        } else {
+            self.consistency_subpath(m - k, start + k, end, false, path);
+            path.push(self.subtree_root(start, start + k));
+        }
+    }
+
+    /// Add the audit path of the leaf to path, the nearest sibling first.
+    fn inclusion_path(&self, index: usize, start: usize, end: usize, path: &mut Vec<Hash>) {
+        let n = end - start;
+        if n <= 1 {
+            return;
+        }
+        let k = split_point(n);
+        if index < k {
+            self.inclusion_path(index, start, start + k, path);
+            path.push(self.subtree_root(start + k, end));
+        } else {
+            self.inclusion_path(index - k, start + k, end, path);
+            path.push(self.subtree_root(start, start + k));
+        }
+    }
+}
+
+
+/// Proof that an entry hash is part of a log with a given Merkle root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InclusionProof {
+    /// Hash of the log entry.
+    pub leaf: Hash,
+
+    /// Position of the entry in the log, starting with 0 for the oldest.
+    pub index: u64,
+
+    /// Number of entries the log had when the proof was created.
+    pub tree_size: u64,
+
+    /// Hashes of the sibling subtrees from the leaf up to the root.
+    pub path: Vec<Hash>
+}
+
+impl Writable for InclusionProof {
+    fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
+        let mut size = 0;
+        size += try!(write_hash(&self.leaf, write));
+        size += try!(write_u64(self.index, write));
+        size += try!(write_u64(self.tree_size, write));
+        size += try!(write_u32(self.path.len() as u32, write));
+        for hash in self.path.iter() {
+            size += try!(write_hash(hash, write));
+        }
+        Ok(size)
+    }
+}
+
+impl Readable for InclusionProof {
+    fn read_from<R: Read, C: ChildReader>(read: &mut R, _: &C)
+            -> Result<InclusionProof, HashIOError> {
+        let leaf = try!(read_hash(read));
+        let index = try!(read_u64(read));
+        let tree_size = try!(read_u64(read));
+        let count = try!(read_u32(read));
+        let mut path = Vec::new();
+        for _ in 0..count {
+            path.push(try!(read_hash(read)));
+        }
+        Ok(InclusionProof {
+            leaf: leaf,
+            index: index,
+            tree_size: tree_size,
+            path: path
+        })
+    }
+}
+
+/// Check the inclusion proof against a trusted Merkle root.
+///
+/// The algorithm of the root is used to hash the nodes.  The log itself
+/// is not required.
+pub fn verify_inclusion(proof: &InclusionProof, root: &Hash) -> bool {
+    let algorithm = match root.algorithm() {
+        Some(algorithm) => algorithm,
+        None => return false
+    };
+    if proof.index >= proof.tree_size {
+        return false;
+    }
+    let mut index = proof.index;
+    let mut last = proof.tree_size - 1;
+    let mut result = leaf_hash(algorithm, &proof.leaf);
+    for sibling in proof.path.iter() {
+        if last == 0 {
+            return false;
+        }
+        if index & 1 == 1 || index == last {
+            result = node_hash(algorithm, sibling, &result);
+            while index & 1 == 0 && index != 0 {
+                index >>= 1;
+                last >>= 1;
+            }
+        } else {
+            result = node_hash(algorithm, &result, sibling);
+        }
+        index >>= 1;
+        last >>= 1;
+    }
+    last == 0 && result == *root
+}
+
+
+/// Proof that a newer head of a log extends an older one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsistencyProof {
+    pub old_head: Hash,
+    pub new_head: Hash,
+
+    /// Number of entries up to the old head.
+    pub old_size: u64,
+
+    /// Number of entries up to the new head.
+    pub new_size: u64,
+
+    /// Hashes which prove that the old tree is a prefix of the new tree.
+    pub path: Vec<Hash>,
+
+    /// Proof that the old head is the last entry of the old tree.
+    pub old_head_proof: InclusionProof
+}
+
+impl Writable for ConsistencyProof {
+    fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
+        let mut size = 0;
+        size += try!(write_hash(&self.old_head, write));
+        size += try!(write_hash(&self.new_head, write));
+        size += try!(write_u64(self.old_size, write));
+        size += try!(write_u64(self.new_size, write));
+        size += try!(write_u32(self.path.len() as u32, write));
+        for hash in self.path.iter() {
+            size += try!(write_hash(hash, write));
+        }
+        size += try!(self.old_head_proof.write_to(write));
+        Ok(size)
+    }
+}
+
+impl Readable for ConsistencyProof {
+    fn read_from<R: Read, C: ChildReader>(read: &mut R, childs: &C)
+            -> Result<ConsistencyProof, HashIOError> {
+        let old_head = try!(read_hash(read));
+        let new_head = try!(read_hash(read));
+        let old_size = try!(read_u64(read));
+        let new_size = try!(read_u64(read));
+        let count = try!(read_u32(read));
+        let mut path = Vec::new();
+        for _ in 0..count {
+            path.push(try!(read_hash(read)));
+        }
+        let old_head_proof = try!(InclusionProof::read_from(read, childs));
+        Ok(ConsistencyProof {
+            old_head: old_head,
+            new_head: new_head,
+            old_size: old_size,
+            new_size: new_size,
+            path: path,
+            old_head_proof: old_head_proof
+        })
+    }
+}
+
+/// Check the consistency proof against the trusted roots of both heads.
+///
+/// It succeeds if the old tree is a prefix of the new tree and the old
+/// head is its last entry.  The log itself is not required.
+pub fn verify_consistency(proof: &ConsistencyProof, old_root: &Hash, new_root: &Hash) -> bool {
+    let algorithm = match new_root.algorithm() {
+        Some(algorithm) => algorithm,
+        None => return false
+    };
+    let head_proof = &proof.old_head_proof;
+    if proof.old_size == 0 || proof.old_size > proof.new_size
+            || head_proof.leaf != proof.old_head
+            || head_proof.index != proof.old_size - 1
+            || head_proof.tree_size != proof.new_size
+            || !verify_inclusion(head_proof, new_root) {
+        return false;
+    }
+    if proof.old_size == proof.new_size {
+        return proof.path.is_empty() && old_root == new_root;
+    }
+
+    let mut path = proof.path.clone();
+    if proof.old_size.is_power_of_two() {
+        path.insert(0, *old_root);
+    }
+    if path.is_empty() {
+        return false;
+    }
+    let mut index = proof.old_size - 1;
+    let mut last = proof.new_size - 1;
+    while index & 1 == 1 {
+        index >>= 1;
+        last >>= 1;
+    }
+    let mut old_result = path[0];
+    let mut new_result = path[0];
+    for sibling in path[1..].iter() {
+        if last == 0 {
+            return false;
+        }
+        if index & 1 == 1 || index == last {
+            old_result = node_hash(algorithm, sibling, &old_result);
+            new_result = node_hash(algorithm, sibling, &new_result);
+            while index & 1 == 0 && index != 0 {
+                index >>= 1;
+                last >>= 1;
+            }
+        } else {
+            new_result = node_hash(algorithm, &new_result, sibling);
+        }
+        index >>= 1;
+        last >>= 1;
+    }
+    last == 0 && old_result == *old_root && new_result == *new_root
+}
+
+
+/// Log which maintains a Merkle tree of its entries.
+pub trait MerkleLog: Log {
+    /// Tree of all entries up to the head.
+    ///
+    /// The tree is borrowed because a log may build it on first use.
+    fn merkle_tree(&self) -> Ref<MerkleTree>;
+
+    /// Merkle root of all entries up to the head.
+    fn merkle_root(&self) -> Hash {
+        self.merkle_tree().root()
+    }
+
+    /// Create a proof that the entry is part of the log.
+    ///
+    /// Returns None if the hash is not an entry of the log.
+    fn inclusion_proof(&self, hash: &Hash) -> Option<InclusionProof> {
+        self.merkle_tree().inclusion_proof(hash)
+    }
+
+    /// Create a proof that new_head extends old_head.
+    ///
+    /// Both are entry hashes of the log and old_head must be an ancestor
+    /// of new_head or new_head itself.
+    ///
+    /// # Errors
+    /// Fails with NotAncestor if old_head is not in the history of
+    /// new_head or if an entry could not be read.
+    fn consistency_proof(&self, old_head: &Hash, new_head: &Hash)
+            -> Result<ConsistencyProof, LogError> {
+        let current;
+        let walked;
+        let tree: &MerkleTree = if self.head_hash() == Some(*new_head) {
+            current = self.merkle_tree();
+            &current
+        } else {
+            walked = try!(MerkleTree::from_head(self, Some(*new_head),
+                                                self.merkle_tree().algorithm()));
+            &walked
+        };
+        let old_index = match tree.index_of(old_head) {
+            Some(index) => index,
+            None => return Err(LogError::NotAncestor {
+                hash: *old_head,
+                head: *new_head
+            })
+        };
+        Ok(ConsistencyProof {
+            old_head: *old_head,
+            new_head: *new_head,
+            old_size: old_index as u64 + 1,
+            new_size: tree.len() as u64,
+            path: tree.consistency_path(old_index + 1),
+            old_head_proof: tree.inclusion_proof(old_head)
+                .expect("The old head was found in the tree")
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::hash::*;
+    use super::super::hashio::*;
+    use super::super::io::*;
+    use super::super::log::*;
+    use super::*;
+
+    #[test]
+    fn inclusion_test() {
+        let mut log = DefaultLog::<String>::default();
+        let hashes: Vec<Hash> = (0..11).map(|i| log.push(format!("entry {}", i))).collect();
+        let root = log.merkle_root();
+        assert_eq!(11, log.merkle_tree().len());
+
+        for hash in hashes.iter() {
+            let proof = log.inclusion_proof(hash).unwrap();
+            assert_eq!(true, proof.path.len() <= 4);
+            assert_eq!(true, verify_inclusion(&proof, &root));
+
+            // The proof survives serialization
+            let mut bytes = Vec::new();
+            proof.write_to(&mut bytes).unwrap();
+            let read_proof = InclusionProof::read_from(&mut &bytes[..], &NoChilds).unwrap();
+            assert_eq!(proof, read_proof);
+        }
+
+        // Wrong entry, wrong position or an older root fail
+        let mut proof = log.inclusion_proof(&hashes[5]).unwrap();
+        assert_eq!(false, verify_inclusion(&proof, &log.merkle_tree().root_at(10).unwrap()));
+        proof.index = 4;
+        assert_eq!(false, verify_inclusion(&proof, &root));
+        proof.index = 5;
+        proof.leaf = hashes[6];
+        assert_eq!(false, verify_inclusion(&proof, &root));
+        assert_eq!(None, log.inclusion_proof(&Hash::hash_bytes(b"missing")));
+
+        // Resetting the head drops the newer entries from the tree
+        log.reset_head(&hashes[3]).unwrap();
+        assert_eq!(4, log.merkle_tree().len());
+        assert_eq!(None, log.inclusion_proof(&hashes[4]));
+    }
+
+    #[test]
+    fn cache_test() {
+        let hashes: Vec<Hash> = (0..13).map(|i| Hash::hash_bytes(format!("{}", i).as_bytes())).collect();
+        let mut tree = MerkleTree::new(HashAlgorithm::default());
+        for hash in hashes.iter() {
+            tree.push(*hash);
+        }
+        let full = tree.clone();
+
+        // Truncated and extended trees have the same nodes as new ones
+        tree.truncate(5);
+        let mut expected = MerkleTree::new(HashAlgorithm::default());
+        for hash in hashes[..5].iter() {
+            expected.push(*hash);
+        }
+        assert_eq!(expected, tree);
+        assert_eq!(full.root_at(5), Some(tree.root()));
+        for hash in hashes[5..].iter() {
+            tree.push(*hash);
+        }
+        assert_eq!(full, tree);
+
+        tree.cut(&hashes[7]);
+        let mut expected = MerkleTree::new(HashAlgorithm::default());
+        for hash in hashes[8..].iter() {
+            expected.push(*hash);
+        }
+        assert_eq!(expected, tree);
+
+        // Following another head keeps the shared entries
+        let mut log = DefaultLog::<String>::default();
+        let hashes: Vec<Hash> = (0..6).map(|i| log.push(format!("entry {}", i))).collect();
+        let mut tree = log.merkle_tree().clone();
+        log.reset_head(&hashes[2]).unwrap();
+        log.push("other".to_string());
+        tree.follow_head(&log, log.head_hash()).unwrap();
+        assert_eq!(&MerkleTree::from_log(&log, HashAlgorithm::default()).unwrap(), &tree);
+        assert_eq!(*log.merkle_tree(), tree);
+    }
+
+    #[test]
+    fn consistency_test() {
+        let mut log = DefaultLog::<String>::default();
+        let hashes: Vec<Hash> = (0..9).map(|i| log.push(format!("entry {}", i))).collect();
+        let tree = log.merkle_tree().clone();
+
+        for old in 0..hashes.len() {
+            for new in old..hashes.len() {
+                let proof = log.consistency_proof(&hashes[old], &hashes[new]).unwrap();
+                let old_root = tree.root_at(old + 1).unwrap();
+                let new_root = tree.root_at(new + 1).unwrap();
+                assert_eq!(true, verify_consistency(&proof, &old_root, &new_root));
+                if old != new {
+                    assert_eq!(false, verify_consistency(&proof, &new_root, &old_root));
+                }
+            }
+        }
+
+        let proof = log.consistency_proof(&hashes[2], &hashes[7]).unwrap();
+        let mut bytes = Vec::new();
+        proof.write_to(&mut bytes).unwrap();
+        assert_eq!(proof, ConsistencyProof::read_from(&mut &bytes[..], &NoChilds).unwrap());
+
+        // A head of another branch is not an ancestor
+        log.reset_head(&hashes[4]).unwrap();
+        let other = log.push("other".to_string());
+        assert_eq!(Err(LogError::NotAncestor { hash: hashes[6], head: other }),
+                   log.consistency_proof(&hashes[6], &other));
+        let proof = log.consistency_proof(&hashes[4], &other).unwrap();
+        assert_eq!(true, verify_consistency(&proof, &tree.root_at(5).unwrap(), &log.merkle_root()));
+        assert_eq!(false, verify_consistency(&proof, &tree.root_at(5).unwrap(), &tree.root()));
+    }
+}
@@ -0,0 +1,519 @@
+//! Single file object storage
+//!
+//! # Usage
+//! DirectoryStore creates one file for each object which leads to a huge
+//! amount of tiny files for a busy log.  PackStore instead appends all
+//! objects to one data file and keeps a sorted index of hashes next to it.
+//!
+//! The pack lives in the `pack` subdirectory of the base path and consists
+//! of two files:
+//!
+//! * `objects.pack`: Append only sequence of records.  Each record
+//!   contains the hash, the length and the raw bytes of the object.
+//! * `objects.idx`: Sorted list of all hashes with the position of the
+//!   object in the data file.  It also stores how much of the data file
+//!   it covers.  If the data file is longer, the missing records are read
+//!   again when the pack is opened.
+//!
+//! A repacked data file is called `objects-<id>.pack` where the id is
+//! stored in the index.  Replacing the index switches to the new data
+//! file in one step, so an interrupted repack leaves the old pack intact.
+//!
+//! The index is only written by `flush`, `repack` and when the store is
+//! dropped.  Objects which were added in the meantime are recovered from
+//! the data file, objects which were deleted in the meantime come back.
+//!
+//! References are stored as files in the base path just like the
+//! DirectoryStore does, so `repack` and `unpack` can convert a loose
+//! object directory into a pack and back in place.  `open_store` picks
+//! the right store for a directory.
+
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::fs::{File, OpenOptions, create_dir_all, remove_dir_all, remove_file, rename};
+use std::path::Path;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::io;
+use hash::*;
+use hashio::*;
+use io::*;
+use store::*;
+
+/// Version of the index format which is written.
+///
+/// Version 0 has no pack id and always uses `objects.pack`.
+pub const PACK_INDEX_VERSION: u32 = 1;
+
+/// Location of an object inside the data file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PackEntry {
+    offset: u64,
+    len: u32
+}
+
+/// Stores all objects in one append only data file with a sorted index.
+pub struct PackStore {
+    pub base_path: String,
+    refs: DirectoryStore,
+    index: RefCell<BTreeMap<Hash, PackEntry>>,
+    pack_id: Cell<u64>,
+    dirty: Cell<bool>
+}
+
+impl PackStore {
+    /// Open the pack in the given directory.
+    ///
+    /// The pack is created if it doesn't exist yet.
+    pub fn new(path: String) -> Result<PackStore, HashIOError> {
+        let pack = PackStore {
+            base_path: path.clone(),
+            refs: DirectoryStore::new(path),
+            index: RefCell::new(BTreeMap::new()),
+            pack_id: Cell::new(0),
+            dirty: Cell::new(false)
+        };
+        try!(create_dir_all(pack.pack_directory()));
+        try!(pack.load());
+        Ok(pack)
+    }
+
+    pub fn pack_directory(&self) -> String {
+        format!("{}/pack", self.base_path)
+    }
+
+    pub fn data_filename(&self) -> String {
+        self.data_filename_for(self.pack_id.get())
+    }
+
+    fn data_filename_for(&self, pack_id: u64) -> String {
+        if pack_id == 0 {
+            format!("{}/objects.pack", self.pack_directory())
+        } else {
+            format!("{}/objects-{:016x}.pack", self.pack_directory(), pack_id)
+        }
+    }
+
+    pub fn index_filename(&self) -> String {
+        format!("{}/objects.idx", self.pack_directory())
+    }
+
+    /// Load the index and read all records which are not covered by it.
+    ///
+    /// # Errors
+    /// Fails with UnsupportedVersion if the index was written by a newer
+    /// version.
+    fn load(&self) -> Result<(), HashIOError> {
+        let mut covered: u64 = 0;
+        if Path::new(&self.index_filename()).exists() {
+            let mut read = try!(File::open(self.index_filename()));
+            let version = try!(read_u32(&mut read));
+            if version > PACK_INDEX_VERSION {
+                return Err(HashIOError::UnsupportedVersion(version));
+            }
+            if version > 0 {
+                self.pack_id.set(try!(read_u64(&mut read)));
+            }
+            covered = try!(read_u64(&mut read));
+            let count = try!(read_u32(&mut read));
+            let mut index = self.index.borrow_mut();
+            for _ in 0..count {
+                let hash = try!(read_hash(&mut read));
+                let offset = try!(read_u64(&mut read));
+                let len = try!(read_u32(&mut read));
+                index.insert(hash, PackEntry { offset: offset, len: len });
+            }
+        }
+        if !Path::new(&self.data_filename()).exists() {
+            try!(File::create(self.data_filename()));
+        }
+        let mut data = try!(OpenOptions::new().read(true).write(true)
+                                .open(self.data_filename()));
+        let data_len = try!(data.metadata()).len();
+        if data_len > covered {
+            try!(data.seek(SeekFrom::Start(covered)));
+            let mut tail = Vec::new();
+            try!(data.read_to_end(&mut tail));
+            let valid = self.scan_records(&tail, covered);
+            // Drop the remains of an interrupted write so new records
+            // are appended at a valid position.
+            if valid < tail.len() {
+                try!(data.set_len(covered + valid as u64));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add all complete records in bytes to the index.
+    ///
+    /// Returns the number of bytes which contained complete records.
+    fn scan_records(&self, bytes: &[u8], start: u64) -> usize {
+        let header_len = 1 + 32 + 4;
+        let mut pos: usize = 0;
+        let mut index = self.index.borrow_mut();
+        while bytes.len() - pos >= header_len {
+            let mut read = &bytes[pos..pos + header_len];
+            let hash = match read_hash(&mut read) {
+                Ok(hash) => hash,
+                Err(_) => break
+            };
+            let len = match read_u32(&mut read) {
+                Ok(len) => len,
+                Err(_) => break
+            };
+            if hash == Hash::None || bytes.len() - pos - header_len < len as usize {
+                break;
+            }
+            index.insert(hash, PackEntry {
+                offset: start + (pos + header_len) as u64,
+                len: len
+            });
+            pos += header_len + len as usize;
+        }
+        pos
+    }
+
+    /// Write the index to disk if objects were added or deleted.
+    ///
+    /// Should be called before the store is dropped because Drop can't
+    /// report an error.
+    pub fn flush(&self) -> Result<(), HashIOError> {
+        if self.dirty.get() {
+            try!(self.write_index());
+        }
+        Ok(())
+    }
+
+    /// Write the index to disk.
+    ///
+    /// The index is written in a temporary file first which is renamed
+    /// afterwards so there is always a valid index.
+    pub fn write_index(&self) -> Result<(), HashIOError> {
+        try!(self.write_index_for(&self.index_filename(), self.pack_id.get(),
+                                  &self.data_filename()));
+        self.dirty.set(false);
+        Ok(())
+    }
+
+    /// Write the index as index of the pack with the given data file.
+    fn write_index_for(&self, filename: &str, pack_id: u64, data_filename: &str)
+            -> Result<(), HashIOError> {
+        let covered = try!(try!(File::open(data_filename)).metadata()).len();
+        let safe_filename = format!("{}_", filename);
+        {
+            let mut write = try!(File::create(&safe_filename));
+            let index = self.index.borrow();
+            try!(write_u32(PACK_INDEX_VERSION, &mut write));
+            try!(write_u64(pack_id, &mut write));
+            try!(write_u64(covered, &mut write));
+            try!(write_u32(index.len() as u32, &mut write));
+            for (hash, entry) in index.iter() {
+                try!(write_hash(hash, &mut write));
+                try!(write_u64(entry.offset, &mut write));
+                try!(write_u32(entry.len, &mut write));
+            }
+        }
+        try!(rename(safe_filename, filename));
+        Ok(())
+    }
+}
+
+impl Drop for PackStore {
+    /// Writes the index if it changed since it was last written.
+    ///
+    /// Errors are ignored, use flush to get them.
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
+
+impl ObjectStore for PackStore {
+    fn get(&self, hash: &Hash) -> Result<Vec<u8>, HashIOError> {
+        let entry = match self.index.borrow().get(hash) {
+            Some(entry) => *entry,
+            None => return Err(HashIOError::IOError(
+                io::Error::new(io::ErrorKind::NotFound,
+                               format!("Object not found: {}", hash.as_string()))))
+        };
+        let mut read = try!(File::open(self.data_filename()));
+        try!(read.seek(SeekFrom::Start(entry.offset)));
+        let mut result = vec![0u8; entry.len as usize];
+        try!(read.read_exact(&mut result));
+        Ok(result)
+    }
+
+    fn put(&self, hash: &Hash, data: &[u8]) -> Result<(), HashIOError> {
+        if self.exists(hash) {
+            return Ok(());
+        }
+        let mut record: Vec<u8> = Vec::with_capacity(data.len() + 37);
+        try!(write_hash(hash, &mut record));
+        try!(write_u32(data.len() as u32, &mut record));
+        let header_len = record.len() as u64;
+        record.extend_from_slice(data);
+
+        let mut write = try!(OpenOptions::new().append(true).open(self.data_filename()));
+        let offset = try!(write.metadata()).len();
+        try!(write.write_all(&record));
+        self.index.borrow_mut().insert(*hash, PackEntry {
+            offset: offset + header_len,
+            len: data.len() as u32
+        });
+        self.dirty.set(true);
+        Ok(())
+    }
+
+    fn exists(&self, hash: &Hash) -> bool {
+        self.index.borrow().contains_key(hash)
+    }
+
+    fn list(&self) -> Result<Vec<Hash>, HashIOError> {
+        Ok(self.index.borrow().keys().cloned().collect())
+    }
+
+    /// Removes the object from the index.
+    ///
+    /// The index is written on the next flush.  The bytes stay in the
+    /// data file until the pack gets repacked.
+    fn delete(&self, hash: &Hash) -> Result<(), HashIOError> {
+        if self.index.borrow_mut().remove(hash).is_none() {
+            return Err(HashIOError::IOError(
+                io::Error::new(io::ErrorKind::NotFound,
+                               format!("Object not found: {}", hash.as_string()))));
+        }
+        self.dirty.set(true);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), HashIOError> {
+        PackStore::flush(self)
+    }
+
+    fn read_ref(&self, name: &str) -> Result<Option<Hash>, HashIOError> {
+        self.refs.read_ref(name)
+    }
+
+    fn write_ref(&self, name: &str, hash: &Hash) -> Result<(), HashIOError> {
+        self.refs.write_ref(name, hash)
+    }
+
+    fn list_refs(&self) -> Result<Vec<String>, HashIOError> {
+        self.refs.list_refs()
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<(), HashIOError> {
+        self.refs.delete_ref(name)
+    }
+}
+
+
+/// True if the directory contains a pack.
+pub fn has_pack(path: &str) -> bool {
+    Path::new(&format!("{}/pack/objects.idx", path)).exists()
+        || Path::new(&format!("{}/pack/objects.pack", path)).exists()
+}
+
+/// Open the objects of the directory in the layout they are stored in.
+///
+/// Returns a PackStore if the directory contains a pack and a
+/// DirectoryStore otherwise.  Loose objects next to a pack are not
+/// visible until they are moved into the pack by `repack`.
+pub fn open_store(path: String) -> Result<Box<ObjectStore>, HashIOError> {
+    if has_pack(&path) {
+        Ok(Box::new(try!(PackStore::new(path))))
+    } else {
+        Ok(Box::new(DirectoryStore::new(path)))
+    }
+}
+
+/// Copy all objects from one store into another.
+///
+/// Returns the number of objects which were copied.
+pub fn copy_objects(from: &ObjectStore, to: &ObjectStore) -> Result<usize, HashIOError> {
+    let mut count = 0;
+    for hash in try!(from.list()) {
+        if !to.exists(&hash) {
+            let bytes = try!(from.get(&hash));
+            try!(to.put(&hash, &bytes));
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Move all loose objects of the directory into its pack.
+///
+/// Objects which are already in the pack stay there, the loose files are
+/// removed after the index was written.  The pack is compacted on the
+/// way, so objects which were deleted from the pack don't use space
+/// anymore.  Returns the number of objects in the pack.
+pub fn repack(path: String) -> Result<usize, HashIOError> {
+    let loose = DirectoryStore::new(path.clone());
+    let new_path = format!("{}/pack/new", path);
+    let count;
+    {
+        // Write a fresh pack next to the old one to get rid of deleted records
+        let old_pack = try!(PackStore::new(path.clone()));
+        remove_dir_all(&new_path).ok();
+        let new_pack = try!(PackStore::new(new_path.clone()));
+        try!(copy_objects(&old_pack, &new_pack));
+        try!(copy_objects(&loose, &new_pack));
+        count = try!(new_pack.list()).len();
+
+        // Move the new data file next to the old one and switch over by
+        // replacing the index
+        let pack_id = old_pack.pack_id.get() + 1;
+        let data_filename = old_pack.data_filename_for(pack_id);
+        try!(rename(new_pack.data_filename(), &data_filename));
+        try!(new_pack.write_index_for(&old_pack.index_filename(), pack_id, &data_filename));
+        new_pack.dirty.set(false);
+        try!(remove_file(old_pack.data_filename()));
+    }
+    try!(remove_dir_all(&new_path));
+    for hash in try!(loose.list()) {
+        try!(loose.delete(&hash));
+    }
+    Ok(count)
+}
+
+/// Move all objects of the pack into loose files and remove the pack.
+///
+/// Returns the number of objects which were unpacked.
+pub fn unpack(path: String) -> Result<usize, HashIOError> {
+    let loose = DirectoryStore::new(path.clone());
+    let pack_directory;
+    let count;
+    {
+        let pack = try!(PackStore::new(path));
+        try!(copy_objects(&pack, &loose));
+        count = try!(pack.list()).len();
+        pack_directory = pack.pack_directory();
+    }
+    try!(remove_dir_all(pack_directory));
+    Ok(count)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::hash::*;
+    use super::super::hashio::*;
+    use super::super::fsck::*;
+    use super::super::io::*;
+    use super::super::iolog::*;
+    use super::super::log::*;
+    use super::super::store::*;
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::Path;
+
+    #[test]
+    fn pack_store_test() {
+        let dir = TestDirectory::new("packtest");
+        let foo = Hash::hash_bytes(b"foo");
+        let bar = Hash::hash_bytes(b"bar");
+        {
+            let pack = PackStore::new(dir.path.clone()).unwrap();
+            pack.put(&foo, b"foo").unwrap();
+            pack.put(&bar, b"bar").unwrap();
+            assert_eq!(b"foo".to_vec(), pack.get(&foo).unwrap());
+            assert_eq!(b"bar".to_vec(), pack.get(&bar).unwrap());
+        }
+        // Reopen with the written index
+        let pack = PackStore::new(dir.path.clone()).unwrap();
+        assert_eq!(2, pack.list().unwrap().len());
+        assert_eq!(b"bar".to_vec(), pack.get(&bar).unwrap());
+        pack.delete(&bar).unwrap();
+        assert_eq!(false, pack.exists(&bar));
+        pack.flush().unwrap();
+        let pack = PackStore::new(dir.path.clone()).unwrap();
+        assert_eq!(vec![foo], pack.list().unwrap());
+    }
+
+    #[test]
+    fn pack_version_test() {
+        let dir = TestDirectory::new("packversiontest");
+        {
+            let pack = PackStore::new(dir.path.clone()).unwrap();
+            pack.put(&Hash::hash_bytes(b"foo"), b"foo").unwrap();
+            pack.flush().unwrap();
+            let mut index = OpenOptions::new().write(true)
+                .open(pack.index_filename()).unwrap();
+            write_u32(PACK_INDEX_VERSION + 1, &mut index).unwrap();
+        }
+        match PackStore::new(dir.path.clone()) {
+            Err(HashIOError::UnsupportedVersion(version)) =>
+                assert_eq!(PACK_INDEX_VERSION + 1, version),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Unknown index version was accepted")
+        }
+    }
+
+    #[test]
+    fn pack_recover_test() {
+        let dir = TestDirectory::new("packrecovertest");
+        let foo = Hash::hash_bytes(b"foo");
+        {
+            let pack = PackStore::new(dir.path.clone()).unwrap();
+            pack.put(&foo, b"foo").unwrap();
+            // Simulate a crash before the index was written
+            ::std::mem::forget(pack);
+        }
+        {
+            // Simulate an interrupted write of a second record
+            let mut data = OpenOptions::new().append(true)
+                .open(&dir.join("pack/objects.pack")).unwrap();
+            data.write_all(&[1u8, 2, 3]).unwrap();
+        }
+        let pack = PackStore::new(dir.path.clone()).unwrap();
+        assert_eq!(vec![foo], pack.list().unwrap());
+        let bar = Hash::hash_bytes(b"bar");
+        pack.put(&bar, b"bar").unwrap();
+        assert_eq!(b"foo".to_vec(), pack.get(&foo).unwrap());
+        assert_eq!(b"bar".to_vec(), pack.get(&bar).unwrap());
+    }
+
+    #[test]
+    fn repack_test() {
+        let dir = TestDirectory::new("repacktest");
+        let foo = Hash::hash_bytes(b"foo");
+        let bar = Hash::hash_bytes(b"bar");
+        let loose = DirectoryStore::new(dir.path.clone());
+        loose.put(&foo, b"foo").unwrap();
+        loose.put(&bar, b"bar").unwrap();
+        loose.write_ref("head", &foo).unwrap();
+
+        assert_eq!(2, repack(dir.path.clone()).unwrap());
+        assert_eq!(0, loose.list().unwrap().len());
+        {
+            let pack = PackStore::new(dir.path.clone()).unwrap();
+            assert_eq!(b"foo".to_vec(), pack.get(&foo).unwrap());
+            assert_eq!(Some(foo), pack.read_ref("head").unwrap());
+            // The old data file was replaced
+            assert_eq!(false, Path::new(&dir.join("pack/objects.pack")).exists());
+            assert_eq!(true, Path::new(&pack.data_filename()).exists());
+        }
+
+        assert_eq!(true, has_pack(&dir.path));
+        assert_eq!(2, unpack(dir.path.clone()).unwrap());
+        assert_eq!(false, has_pack(&dir.path));
+        assert_eq!(2, loose.list().unwrap().len());
+        assert_eq!(b"bar".to_vec(), loose.get(&bar).unwrap());
+    }
+
+    #[test]
+    fn open_packed_log_test() {
+        let dir = TestDirectory::new("packedlogtest");
+        let head = {
+            let mut log = IOLog::<String>::new(dir.path.clone());
+            log.push("one".to_string());
+            log.push("two".to_string())
+        };
+        repack(dir.path.clone()).unwrap();
+
+        let log = IOLog::<String>::new(dir.path.clone());
+        assert_eq!(Some(head), log.head_hash());
+        assert_eq!(None, verify_log(&log));
+        assert_eq!(true, fsck::<String>(dir.path.clone()).unwrap().is_ok());
+    }
+}
@@ -0,0 +1,229 @@
+//! Ed25519 signatures for log entries
+//!
+//! # Usage
+//! A hash chain only shows manipulations to somebody who kept an old
+//! hash.  Anyone with write access could rebuild the log and publish a new
+//! valid chain.  Logs can therefore sign each new entry with a SigningKey.
+//! verify_log_with_keys then only accepts entries which are signed by one
+//! of the trusted public keys.
+//!
+//! Keys are stored in local files: a signing key file contains the 32 byte
+//! seed, a public key file the 32 byte public key.  On Unix, signing key
+//! files are only readable by their owner.
+
+extern crate crypto;
+extern crate rand;
+
+use std::fs::{File, OpenOptions, remove_file, rename};
+use std::io;
+use std::io::{Read, Write};
+use self::crypto::ed25519;
+use hash::*;
+use hashio::*;
+use io::*;
+
+
+/// Public part of a key which is used to verify signatures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PublicKey(pub [u8; 32]);
+
+/// Ed25519 signature of a hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 64]);
+
+/// Private key which signs log entries.
+#[derive(Clone)]
+pub struct SigningKey {
+    seed: [u8; 32],
+    secret: [u8; 64],
+    public_key: PublicKey
+}
+
+impl SigningKey {
+    /// Derive the key from a 32 byte seed.
+    pub fn from_seed(seed: [u8; 32]) -> SigningKey {
+        let (secret, public) = ed25519::keypair(&seed);
+        SigningKey {
+            seed: seed,
+            secret: secret,
+            public_key: PublicKey(public)
+        }
+    }
+
+    /// Create a new random key.
+    ///
+    /// The random generator must be cryptographically secure like OsRng.
+    pub fn generate<R: rand::Rng>(rng: &mut R) -> SigningKey {
+        let mut seed = [0u8; 32];
+        rng.fill_bytes(&mut seed);
+        SigningKey::from_seed(seed)
+    }
+
+    /// Read the seed from the file.
+    pub fn load(path: &str) -> Result<SigningKey, HashIOError> {
+        let mut file = try!(File::open(path));
+        let mut seed = [0u8; 32];
+        try!(read_exactly(&mut file, &mut seed));
+        Ok(SigningKey::from_seed(seed))
+    }
+
+    /// Write the seed to the file.
+    ///
+    /// Everybody who can read the file is able to sign entries, so on Unix
+    /// it's created with the permissions 0600.  The seed is written to
+    /// `<path>_` first and renamed afterwards like DirectoryStore does it,
+    /// so the file is never left half written.  An existing file is
+    /// replaced and gets the same permissions.
+    pub fn save(&self, path: &str) -> Result<(), HashIOError> {
+        let safe_path = format!("{}_", path);
+        // A left over file would keep its permissions
+        remove_file(&safe_path).ok();
+        {
+            let mut file = try!(create_private_file(&safe_path));
+            try!(file.write_all(&self.seed));
+        }
+        try!(rename(&safe_path, path));
+        Ok(())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Sign the hash.
+    pub fn sign(&self, hash: &Hash) -> EntrySignature {
+        EntrySignature {
+            public_key: self.public_key,
+            signature: Signature(ed25519::signature(&*hash.get_bytes(), &self.secret))
+        }
+    }
+}
+
+/// Create a new file which only the owner can read and write.
+#[cfg(unix)]
+fn create_private_file(path: &str) -> Result<File, io::Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+/// Create a new file, permissions are left to the platform.
+#[cfg(not(unix))]
+fn create_private_file(path: &str) -> Result<File, io::Error> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+impl PublicKey {
+    /// Read the public key from the file.
+    pub fn load(path: &str) -> Result<PublicKey, HashIOError> {
+        let mut file = try!(File::open(path));
+        let mut bytes = [0u8; 32];
+        try!(read_exactly(&mut file, &mut bytes));
+        Ok(PublicKey(bytes))
+    }
+
+    /// Write the public key to the file.
+    pub fn save(&self, path: &str) -> Result<(), HashIOError> {
+        let mut file = try!(File::create(path));
+        try!(file.write_all(&self.0));
+        Ok(())
+    }
+}
+
+
+/// Signature of a log entry together with the key which created it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntrySignature {
+    pub public_key: PublicKey,
+    pub signature: Signature
+}
+
+impl EntrySignature {
+    /// True if the signature is valid for the hash.
+    pub fn verify(&self, hash: &Hash) -> bool {
+        ed25519::verify(&*hash.get_bytes(), &self.public_key.0, &self.signature.0)
+    }
+
+    /// Read the signature without a ChildReader since it has no childs.
+    pub fn read<R: Read>(read: &mut R) -> Result<EntrySignature, HashIOError> {
+        let mut public_key = [0u8; 32];
+        let mut signature = [0u8; 64];
+        try!(read_exactly(read, &mut public_key));
+        try!(read_exactly(read, &mut signature));
+        Ok(EntrySignature {
+            public_key: PublicKey(public_key),
+            signature: Signature(signature)
+        })
+    }
+}
+
+impl Writable for EntrySignature {
+    fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
+        try!(write.write_all(&self.public_key.0));
+        try!(write.write_all(&self.signature.0));
+        Ok(96)
+    }
+}
+
+impl Readable for EntrySignature {
+    fn read_from<R: Read, C: ChildReader>(read: &mut R, _: &C)
+            -> Result<EntrySignature, HashIOError> {
+        EntrySignature::read(read)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::hash::*;
+    use super::super::log::*;
+    use super::*;
+    use super::super::store::*;
+    use std::fs::metadata;
+
+    #[test]
+    fn key_file_test() {
+        let dir = TestDirectory::new("keytest");
+        let key = SigningKey::from_seed([7u8; 32]);
+        key.save(&dir.join("key")).unwrap();
+        key.public_key().save(&dir.join("key.pub")).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = metadata(&dir.join("key")).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o777);
+        }
+
+        let loaded = SigningKey::load(&dir.join("key")).unwrap();
+        let public_key = PublicKey::load(&dir.join("key.pub")).unwrap();
+        assert_eq!(key.public_key(), public_key);
+
+        let hash = Hash::hash_bytes(b"entry");
+        let signature = loaded.sign(&hash);
+        assert_eq!(public_key, signature.public_key);
+        assert_eq!(true, signature.verify(&hash));
+        assert_eq!(false, signature.verify(&Hash::hash_bytes(b"other")));
+    }
+
+    #[test]
+    fn signed_log_test() {
+        let key = SigningKey::from_seed([1u8; 32]);
+        let other_key = SigningKey::from_seed([2u8; 32]);
+        let mut log = DefaultLog::<String>::default().with_signing_key(key.clone());
+        let one = log.push("one".to_string());
+        log.push("two".to_string());
+        assert_eq!(None, verify_log(&log));
+        assert_eq!(None, verify_log_with_keys(&log, &[other_key.public_key(), key.public_key()]));
+        assert_eq!(Some(LogVerifyFailure::UntrustedSignature {
+                       hash: one,
+                       public_key: key.public_key()
+                   }),
+                   verify_log_with_keys(&log, &[other_key.public_key()]));
+
+        // A rebuilt log has valid hashes but no signatures
+        let rebuilt: DefaultLog<String> = rebuild_log(&log).unwrap();
+        assert_eq!(None, verify_log(&rebuilt));
+        assert_eq!(Some(LogVerifyFailure::UnsignedEntry(one)),
+                   verify_log_with_keys(&rebuilt, &[key.public_key()]));
+    }
+}
@@ -0,0 +1,603 @@
+//! Storage backends for HashIO
+//!
+//! # Usage
+//! HashIO does not access the file system itself.  It serializes the
+//! values and hands the raw bytes to an ObjectStore which is responsible
+//! to persist them by their hash.  Next to the objects, a store also
+//! keeps named references (like the `head` of a log) which point to
+//! an object hash.
+//!
+//! DirectoryStore is the default implementation and stores every object
+//! in its own file in a two level directory layout.  MemoryStore keeps
+//! everything in memory which is useful for tests and ephemeral logs.
+
+use std::io::{Read, Write};
+use std::fs::{File, create_dir_all, read_dir, remove_file, rename};
+use std::path::Path;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use hash::*;
+use hashio::*;
+use io::*;
+
+
+/// Stores raw object bytes by their hash and named references.
+pub trait ObjectStore {
+    /// Read the raw bytes of the object with the given hash.
+    ///
+    /// # Errors
+    /// Returns an error if the object doesn't exist or can't be read.
+    fn get(&self, hash: &Hash) -> Result<Vec<u8>, HashIOError>;
+
+    /// Store raw bytes for the given hash.
+    ///
+    /// Implementations must make sure that either the complete object
+    /// or nothing is stored.
+    fn put(&self, hash: &Hash, data: &[u8]) -> Result<(), HashIOError>;
+
+    /// Check if an object for the hash is stored.
+    fn exists(&self, hash: &Hash) -> bool;
+
+    /// Returns the hashes of all stored objects.
+    fn list(&self) -> Result<Vec<Hash>, HashIOError>;
+
+    /// Returns the hashes of all objects whose hex string starts with
+    /// the given lower case prefix.
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<Hash>, HashIOError> {
+        Ok(try!(self.list()).into_iter()
+            .filter(|hash| hash.as_string().starts_with(prefix))
+            .collect())
+    }
+
+    /// Remove the object with the given hash.
+    fn delete(&self, hash: &Hash) -> Result<(), HashIOError>;
+
+    /// Read the hash a named reference points to.
+    ///
+    /// Returns None if the reference doesn't exist.
+    fn read_ref(&self, name: &str) -> Result<Option<Hash>, HashIOError>;
+
+    /// Let the named reference point to the given hash.
+    fn write_ref(&self, name: &str, hash: &Hash) -> Result<(), HashIOError>;
+
+    /// Returns the names of all references.
+    fn list_refs(&self) -> Result<Vec<String>, HashIOError>;
+
+    /// Remove the named reference.
+    fn delete_ref(&self, name: &str) -> Result<(), HashIOError>;
+
+    /// Write changes which are only kept in memory to disk.
+    fn flush(&self) -> Result<(), HashIOError> {
+        Ok(())
+    }
+}
+
+
+impl<S: ObjectStore> ChildReader for S {
+    fn read_child<T: Readable>(&self, hash: &Hash) -> Result<T, HashIOError> {
+        if *hash == Hash::None {
+            return Err(HashIOError::Undefined("Cannot get an object for an empty hash".to_string()));
+        }
+        let bytes = try!(self.get(hash));
+        let mut read: &[u8] = &bytes;
+        T::read_from(&mut read, self)
+    }
+}
+
+
+/// Stores each object in its own file.
+///
+/// The first two hex characters of the hash are used as directory name
+/// and the rest as filename.  References are stored as files directly in
+/// the base directory.
+pub struct DirectoryStore {
+    pub base_path: String
+}
+
+impl DirectoryStore {
+    pub fn new(path: String) -> DirectoryStore {
+        DirectoryStore {
+            base_path: path
+        }
+    }
+
+    pub fn directory_for_hash(&self, hash: &Hash) -> String {
+        self.directory_for_prefix(&hash.as_string())
+    }
+
+    /// Directory of all objects whose hex string starts with the prefix.
+    ///
+    /// The prefix must have at least two characters.
+    pub fn directory_for_prefix(&self, prefix: &str) -> String {
+        let mut result = String::new();
+        result.push_str(&self.base_path);
+        result.push('/');
+        result.push_str(&prefix[0..2]);
+        result.push('/');
+        result
+    }
+
+    /// Filename of the object.
+    ///
+    /// Objects which are not hashed with sha3 get the name of their
+    /// algorithm as extension.
+    pub fn filename_for_hash(&self, hash: &Hash) -> String {
+        let hash_str = hash.as_string();
+        let mut result = self.directory_for_hash(hash);
+        result.push_str(&hash_str[2..]);
+        match hash.algorithm() {
+            None | Some(HashAlgorithm::Sha3) => (),
+            Some(algorithm) => {
+                result.push('.');
+                result.push_str(algorithm.name());
+            }
+        }
+        result
+    }
+
+    pub fn filename_for_ref(&self, name: &str) -> String {
+        format!("{}/{}", self.base_path, name)
+    }
+
+    /// Add all references in the directory and its subdirectories.
+    ///
+    /// They are named by their path relative to the base directory.
+    fn list_ref_directory(&self, dir_name: &str, result: &mut Vec<String>)
+            -> Result<(), HashIOError> {
+        for entry in try!(read_dir(self.filename_for_ref(dir_name))) {
+            let entry = try!(entry);
+            let name = format!("{}/{}", dir_name, entry.file_name().to_string_lossy());
+            let file_type = try!(entry.file_type());
+            if file_type.is_dir() {
+                try!(self.list_ref_directory(&name, result));
+            } else if file_type.is_file() {
+                result.push(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the hashes of all objects in the directory to the result.
+    fn list_directory(&self, dir_name: &str, result: &mut Vec<Hash>) -> Result<(), HashIOError> {
+        for file_entry in try!(read_dir(self.directory_for_prefix(dir_name))) {
+            let file_entry = try!(file_entry);
+            let file_name = file_entry.file_name().to_string_lossy().into_owned();
+            let (hex_name, algorithm) = match file_name.find('.') {
+                None => (&file_name[..], Some(HashAlgorithm::Sha3)),
+                Some(pos) => (&file_name[..pos],
+                              HashAlgorithm::from_name(&file_name[pos + 1..]))
+            };
+            // Skips unfinished files which end with an underscore
+            if hex_name.len() != 62 || !is_hex_string(hex_name) {
+                continue;
+            }
+            if let Some(algorithm) = algorithm {
+                if let Ok(hash) = Hash::from_string_with(format!("{}{}", dir_name, hex_name),
+                                                         algorithm) {
+                    result.push(hash);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the unfinished files of interrupted writes.
+    ///
+    /// They are named like the object with a trailing underscore.
+    pub fn temp_files(&self) -> Result<Vec<String>, HashIOError> {
+        let mut result = Vec::new();
+        if !Path::new(&self.base_path).exists() {
+            return Ok(result);
+        }
+        for dir_entry in try!(read_dir(&self.base_path)) {
+            let dir_entry = try!(dir_entry);
+            let dir_name = dir_entry.file_name().to_string_lossy().into_owned();
+            if dir_name.len() != 2 || !is_hex_string(&dir_name)
+                    || !try!(dir_entry.file_type()).is_dir() {
+                continue;
+            }
+            for file_entry in try!(read_dir(dir_entry.path())) {
+                let file_entry = try!(file_entry);
+                let file_name = file_entry.file_name().to_string_lossy().into_owned();
+                if file_name.ends_with('_') {
+                    result.push(file_entry.path().to_string_lossy().into_owned());
+                }
+            }
+        }
+        result.sort();
+        Ok(result)
+    }
+}
+
+fn is_hex_string(s: &str) -> bool {
+    s.bytes().all(|c| (c >= b'0' && c <= b'9') || (c >= b'a' && c <= b'f'))
+}
+
+impl ObjectStore for DirectoryStore {
+    fn get(&self, hash: &Hash) -> Result<Vec<u8>, HashIOError> {
+        let filename = self.filename_for_hash(hash);
+        let mut read = try!(File::open(filename));
+        let mut result = Vec::new();
+        try!(read.read_to_end(&mut result));
+        Ok(result)
+    }
+
+    fn put(&self, hash: &Hash, data: &[u8]) -> Result<(), HashIOError> {
+        // First write in a slightly modified file which will be renamed when writing was
+        // finished.  So we only have valid files or nothing on the expected position but
+        // nothing unfinished.
+        let filename = self.filename_for_hash(hash);
+        let safe_filename = format!("{}_", filename);
+        let dir = self.directory_for_hash(hash);
+        try!(create_dir_all(dir));
+        {
+            let mut write = try!(File::create(Path::new(&safe_filename)));
+            try!(write.write_all(data));
+            // 'write' will go out of scope now and so the file handle will be closed
+        }
+        try!(rename(safe_filename, filename));
+        Ok(())
+    }
+
+    fn exists(&self, hash: &Hash) -> bool {
+        Path::new(&self.filename_for_hash(hash)).exists()
+    }
+
+    fn list(&self) -> Result<Vec<Hash>, HashIOError> {
+        let mut result = Vec::new();
+        if !Path::new(&self.base_path).exists() {
+            return Ok(result);
+        }
+        for dir_entry in try!(read_dir(&self.base_path)) {
+            let dir_entry = try!(dir_entry);
+            let dir_name = dir_entry.file_name().to_string_lossy().into_owned();
+            if dir_name.len() != 2 || !is_hex_string(&dir_name)
+                    || !try!(dir_entry.file_type()).is_dir() {
+                continue;
+            }
+            try!(self.list_directory(&dir_name, &mut result));
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    /// Only reads the directory of the first two characters.
+    fn find_prefix(&self, prefix: &str) -> Result<Vec<Hash>, HashIOError> {
+        let mut result = Vec::new();
+        if prefix.len() < 2 || !is_hex_string(prefix)
+                || !Path::new(&self.directory_for_prefix(prefix)).exists() {
+            return Ok(result);
+        }
+        try!(self.list_directory(&prefix[0..2], &mut result));
+        result.retain(|hash| hash.as_string().starts_with(prefix));
+        result.sort();
+        Ok(result)
+    }
+
+    fn delete(&self, hash: &Hash) -> Result<(), HashIOError> {
+        try!(remove_file(self.filename_for_hash(hash)));
+        Ok(())
+    }
+
+    fn read_ref(&self, name: &str) -> Result<Option<Hash>, HashIOError> {
+        let filename = self.filename_for_ref(name);
+        if !Path::new(&filename).exists() {
+            return Ok(None);
+        }
+        let mut file = try!(File::open(filename));
+        let hash = try!(read_hash(&mut file));
+        Ok(match hash {
+            Hash::None => None,
+            _ => Some(hash)
+        })
+    }
+
+    fn write_ref(&self, name: &str, hash: &Hash) -> Result<(), HashIOError> {
+        let filename = self.filename_for_ref(name);
+        if let Some(parent) = Path::new(&filename).parent() {
+            try!(create_dir_all(parent));
+        }
+        let mut file = try!(File::create(filename));
+        try!(write_hash(hash, &mut file));
+        Ok(())
+    }
+
+    /// Lists the files in the base directory and everything below `refs`
+    /// and `backups`.
+    ///
+    /// Other directories contain objects or packs.
+    fn list_refs(&self) -> Result<Vec<String>, HashIOError> {
+        let mut result = Vec::new();
+        if !Path::new(&self.base_path).exists() {
+            return Ok(result);
+        }
+        for entry in try!(read_dir(&self.base_path)) {
+            let entry = try!(entry);
+            let file_type = try!(entry.file_type());
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if file_type.is_file() {
+                result.push(name);
+            } else if file_type.is_dir() && (name == "refs" || name == "backups") {
+                try!(self.list_ref_directory(&name, &mut result));
+            }
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<(), HashIOError> {
+        try!(remove_file(self.filename_for_ref(name)));
+        Ok(())
+    }
+}
+
+
+
+/// Keeps all objects and references in memory.
+///
+/// Clones share the same content, so a store can be handed to a HashIO
+/// and still be inspected or reopened by another log afterwards.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    objects: Rc<RefCell<BTreeMap<Hash, Vec<u8>>>>,
+    refs: Rc<RefCell<BTreeMap<String, Hash>>>
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+fn not_found_error(msg: String) -> HashIOError {
+    HashIOError::IOError(io::Error::new(io::ErrorKind::NotFound, msg))
+}
+
+impl ObjectStore for MemoryStore {
+    fn get(&self, hash: &Hash) -> Result<Vec<u8>, HashIOError> {
+        match self.objects.borrow().get(hash) {
+            Some(bytes) => Ok(bytes.clone()),
+            None => Err(not_found_error(format!("Object not found: {}", hash.as_string())))
+        }
+    }
+
+    fn put(&self, hash: &Hash, data: &[u8]) -> Result<(), HashIOError> {
+        self.objects.borrow_mut().insert(*hash, data.to_vec());
+        Ok(())
+    }
+
+    fn exists(&self, hash: &Hash) -> bool {
+        self.objects.borrow().contains_key(hash)
+    }
+
+    fn list(&self) -> Result<Vec<Hash>, HashIOError> {
+        Ok(self.objects.borrow().keys().cloned().collect())
+    }
+
+    fn delete(&self, hash: &Hash) -> Result<(), HashIOError> {
+        match self.objects.borrow_mut().remove(hash) {
+            Some(_) => Ok(()),
+            None => Err(not_found_error(format!("Object not found: {}", hash.as_string())))
+        }
+    }
+
+    fn read_ref(&self, name: &str) -> Result<Option<Hash>, HashIOError> {
+        Ok(self.refs.borrow().get(name).cloned())
+    }
+
+    fn write_ref(&self, name: &str, hash: &Hash) -> Result<(), HashIOError> {
+        self.refs.borrow_mut().insert(name.to_string(), *hash);
+        Ok(())
+    }
+
+    fn list_refs(&self) -> Result<Vec<String>, HashIOError> {
+        Ok(self.refs.borrow().keys().cloned().collect())
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<(), HashIOError> {
+        match self.refs.borrow_mut().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(not_found_error(format!("Reference not found: {}", name)))
+        }
+    }
+}
+
+
+/// Unique directory for a test which is removed when it's dropped.
+///
+/// Tests run in parallel, so every test needs its own directory.
+#[cfg(test)]
+pub struct TestDirectory {
+    pub path: String
+}
+
+#[cfg(test)]
+impl TestDirectory {
+    /// Create an empty directory in the temp directory of the system.
+    pub fn new(name: &str) -> TestDirectory {
+        use std::env::temp_dir;
+        use std::process;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = format!("{}/tbd-{}-{}-{}", temp_dir().to_string_lossy(), name,
+                           process::id(), COUNTER.fetch_add(1, Ordering::SeqCst));
+        ::std::fs::remove_dir_all(&path).ok();
+        create_dir_all(&path).unwrap();
+        TestDirectory {
+            path: path
+        }
+    }
+
+    /// Path of an entry inside the directory.
+    pub fn join(&self, name: &str) -> String {
+        format!("{}/{}", self.path, name)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDirectory {
+    fn drop(&mut self) {
+        ::std::fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+/// Memory store which refuses to write when it's full.
+///
+/// Clones share the flags, so a test can fill the store of a log.  If
+/// only `objects_full` is set, references can still be written.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct FullStore {
+    pub store: MemoryStore,
+    pub full: Rc<::std::cell::Cell<bool>>,
+    pub objects_full: Rc<::std::cell::Cell<bool>>
+}
+
+#[cfg(test)]
+impl FullStore {
+    fn check_object(&self) -> Result<(), HashIOError> {
+        if self.objects_full.get() {
+            Err(HashIOError::IOError(io::Error::new(io::ErrorKind::Other, "disk full")))
+        } else {
+            self.check()
+        }
+    }
+
+    fn check(&self) -> Result<(), HashIOError> {
+        if self.full.get() {
+            Err(HashIOError::IOError(io::Error::new(io::ErrorKind::Other, "disk full")))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+impl ObjectStore for FullStore {
+    fn get(&self, hash: &Hash) -> Result<Vec<u8>, HashIOError> {
+        self.store.get(hash)
+    }
+
+    fn put(&self, hash: &Hash, data: &[u8]) -> Result<(), HashIOError> {
+        try!(self.check_object());
+        self.store.put(hash, data)
+    }
+
+    fn exists(&self, hash: &Hash) -> bool {
+        self.store.exists(hash)
+    }
+
+    fn list(&self) -> Result<Vec<Hash>, HashIOError> {
+        self.store.list()
+    }
+
+    fn delete(&self, hash: &Hash) -> Result<(), HashIOError> {
+        self.store.delete(hash)
+    }
+
+    fn read_ref(&self, name: &str) -> Result<Option<Hash>, HashIOError> {
+        self.store.read_ref(name)
+    }
+
+    fn write_ref(&self, name: &str, hash: &Hash) -> Result<(), HashIOError> {
+        try!(self.check());
+        self.store.write_ref(name, hash)
+    }
+
+    fn list_refs(&self) -> Result<Vec<String>, HashIOError> {
+        self.store.list_refs()
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<(), HashIOError> {
+        self.store.delete_ref(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::hash::*;
+    use super::*;
+
+    #[test]
+    fn directory_store_test() {
+        let dir = TestDirectory::new("storetest");
+        let store = DirectoryStore::new(dir.path.clone());
+        let hash = Hash::hash_bytes(b"foo");
+        assert_eq!(false, store.exists(&hash));
+        store.put(&hash, b"foo").unwrap();
+        assert_eq!(true, store.exists(&hash));
+        assert_eq!(b"foo".to_vec(), store.get(&hash).unwrap());
+        assert_eq!(vec![hash], store.list().unwrap());
+
+        store.write_ref("head", &hash).unwrap();
+        assert_eq!(Some(hash), store.read_ref("head").unwrap());
+        assert_eq!(None, store.read_ref("other").unwrap());
+        assert_eq!(vec!["head".to_string()], store.list_refs().unwrap());
+
+        // Nested references are listed with their path
+        store.write_ref("refs/feature/a", &hash).unwrap();
+        assert_eq!(Some(hash), store.read_ref("refs/feature/a").unwrap());
+        assert_eq!(vec!["head".to_string(), "refs/feature/a".to_string()],
+                   store.list_refs().unwrap());
+        store.delete_ref("refs/feature/a").unwrap();
+
+        store.delete(&hash).unwrap();
+        store.delete_ref("head").unwrap();
+        assert_eq!(false, store.exists(&hash));
+        assert_eq!(0, store.list().unwrap().len());
+        assert_eq!(0, store.list_refs().unwrap().len());
+    }
+
+    #[test]
+    fn prefix_test() {
+        let dir = TestDirectory::new("prefixtest");
+        let hashio = HashIO::new(dir.path.clone());
+        let one = Hash::from_string(format!("abcd{}", "1".repeat(60))).unwrap();
+        let two = Hash::from_string(format!("abce{}", "2".repeat(60))).unwrap();
+        hashio.store().put(&one, b"one").unwrap();
+        hashio.store().put(&two, b"two").unwrap();
+
+        // The old path accessors still work
+        assert_eq!(Some(&dir.path[..]), hashio.base_path());
+        #[allow(deprecated)]
+        {
+            let store = DirectoryStore::new(dir.path.clone());
+            assert_eq!(store.filename_for_hash(&one), hashio.filename_for_hash(&one));
+            assert_eq!(store.directory_for_hash(&one), hashio.directory_for_hash(&one));
+        }
+
+        assert_eq!(one, hashio.resolve_prefix("abcd").unwrap());
+        assert_eq!(two, hashio.resolve_prefix("ABCE2").unwrap());
+        match hashio.resolve_prefix("abc") {
+            Err(HashIOError::AmbiguousPrefix(_, ref hashes)) => assert_eq!(&vec![one, two], hashes),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+        match hashio.resolve_prefix("abcf") {
+            Err(HashIOError::IOError(ref err)) => assert_eq!(io::ErrorKind::NotFound, err.kind()),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+        assert_eq!(true, hashio.resolve_prefix("a").is_err());
+        assert_eq!(true, hashio.resolve_prefix("abxy").is_err());
+    }
+
+    #[test]
+    fn memory_store_test() {
+        let store = MemoryStore::new();
+        let shared = store.clone();
+        let hash = Hash::hash_bytes(b"foo");
+        store.put(&hash, b"foo").unwrap();
+        store.write_ref("head", &hash).unwrap();
+
+        // Clones see the same content
+        assert_eq!(true, shared.exists(&hash));
+        assert_eq!(b"foo".to_vec(), shared.get(&hash).unwrap());
+        assert_eq!(Some(hash), shared.read_ref("head").unwrap());
+
+        shared.delete(&hash).unwrap();
+        assert_eq!(false, store.exists(&hash));
+        assert_eq!(true, store.get(&hash).is_err());
+    }
+}
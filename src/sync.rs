@@ -0,0 +1,476 @@
+//! Synchronization of logs between two repositories
+//!
+//! # Usage
+//! A repository can be copied to another one, like a laptop and a shared
+//! network mount, by LogSync.  All objects which are reachable from the
+//! head of a branch in the local repository and missing in the remote one
+//! are copied.  The childs of an object are always copied before the
+//! object itself, just like HashIO::put stores them.  An interrupted sync
+//! therefore never leaves an object without its childs behind.
+//!
+//! Afterwards the branch of the remote repository is moved to the new
+//! head.  This is only done if the remote head is part of the local
+//! history, otherwise remote entries would get lost.  Such an update is
+//! refused unless the sync is forced; merge the remote history first.
+//! A forced sync keeps the old remote head as `backups/<branch>/<time>`
+//! reference.
+//!
+//! A sync with a depth only copies the newest entries and records anchors
+//! where the history was cut, so the remote repository becomes a shallow
+//! clone.  The anchors of a shallow local repository are copied as well.
+//! A sync into a shallow clone copies the history behind its anchors and
+//! removes the anchors which are not needed anymore.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use checkpoint::*;
+use hash::*;
+use hashio::*;
+use io::*;
+use iolog::*;
+use log::*;
+use store::*;
+
+
+/// ChildReader which records the read hashes, childs first.
+struct CollectReader<'a> {
+    store: &'a ObjectStore,
+    hashes: RefCell<Vec<Hash>>
+}
+
+impl<'a> ChildReader for CollectReader<'a> {
+    fn read_child<T: Readable>(&self, hash: &Hash) -> Result<T, HashIOError> {
+        let bytes = try!(self.store.get(hash));
+        let mut read: &[u8] = &bytes;
+        let result = try!(T::read_from(&mut read, self));
+        self.hashes.borrow_mut().push(*hash);
+        Ok(result)
+    }
+}
+
+
+/// Result of a sync.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncReport {
+    /// Head of the branch in the remote repository before the sync.
+    pub old_head: Option<Hash>,
+
+    /// Head of the branch in both repositories after the sync.
+    pub new_head: Hash,
+
+    /// Number of objects which were copied.
+    pub copied: usize
+}
+
+/// Copies the history of a branch to another repository.
+///
+/// # Examples
+/// ```
+/// use tbd::hashio::*;
+/// use tbd::iolog::*;
+/// use tbd::log::*;
+/// use tbd::store::*;
+/// use tbd::sync::*;
+///
+/// let local = MemoryStore::new();
+/// let remote = MemoryStore::new();
+/// let mut log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(local.clone())));
+/// let head = log.push("one".to_string());
+///
+/// let report = LogSync::new().sync::<String>(&log.hashio,
+///         &HashIO::with_store(Box::new(remote.clone()))).unwrap();
+/// assert_eq!(2, report.copied);
+/// let remote_log = IOLog::<String>::with_hashio(HashIO::with_store(Box::new(remote.clone())));
+/// assert_eq!(Some(head), remote_log.head_hash());
+/// ```
+pub struct LogSync {
+    branch: String,
+    force: bool,
+    depth: Option<usize>
+}
+
+impl LogSync {
+    /// Sync of the main branch which refuses non fast forward updates.
+    pub fn new() -> LogSync {
+        LogSync {
+            branch: MAIN_BRANCH.to_string(),
+            force: false,
+            depth: None
+        }
+    }
+
+    /// Sync the given branch instead of the main branch.
+    pub fn with_branch(mut self, name: &str) -> LogSync {
+        self.branch = name.to_string();
+        self
+    }
+
+    /// Move the remote branch even if remote entries get lost.
+    pub fn force(mut self) -> LogSync {
+        self.force = true;
+        self
+    }
+
+    /// Only copy the newest entries of the branch, at least the head.
+    ///
+    /// Older entries which are not in the remote repository yet are cut
+    /// off by anchors.
+    pub fn with_depth(mut self, depth: usize) -> LogSync {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Copy the branch from the local to the remote repository.
+    ///
+    /// All log items must contain values of type T.  If the latest
+    /// checkpoint of the local repository belongs to the synced main
+    /// head, it's copied too.
+    ///
+    /// # Errors
+    /// Fails with BranchNotFound if the local branch doesn't exist and
+    /// with NotAncestor if the remote head is not in the local history and
+    /// the sync is not forced.  Nothing is copied in these cases.
+    pub fn sync<T>(&self, local: &HashIO, remote: &HashIO) -> Result<SyncReport, LogError>
+            where T: Readable {
+        let source = local.store();
+        let target = remote.store();
+        let head = match try!(read_branch_head(source, &self.branch)) {
+            Some(hash) => hash,
+            None => return Err(LogError::BranchNotFound(self.branch.clone()))
+        };
+        let old_head = try!(read_branch_head(target, &self.branch));
+        let lost_head = try!(lost_branch_head(source, old_head, head));
+        if let Some(lost_head) = lost_head {
+            if !self.force {
+                return Err(LogError::NotAncestor {
+                    hash: lost_head,
+                    head: head
+                });
+            }
+        }
+
+        let mut anchors = try!(read_anchors(source));
+        if let Some(depth) = self.depth {
+            anchors.extend(try!(stored_anchors(source, head, depth, &anchors)));
+        }
+        // Entries of a shallow target may miss their history
+        let target_anchors = try!(read_anchors(target));
+        let objects = try!(reachable_objects::<T>(source, head, &|hash| {
+            anchors.contains(hash) || (target_anchors.is_empty() && target.exists(hash))
+        }));
+        let mut copied = 0;
+        for hash in objects.iter() {
+            if !target.exists(hash) {
+                let bytes = try!(source.get(hash));
+                try!(target.put(hash, &bytes));
+                copied += 1;
+            }
+        }
+
+        for anchor in anchors.iter() {
+            if !target.exists(anchor) {
+                try!(write_anchor(target, anchor));
+            }
+        }
+        for anchor in target_anchors.iter() {
+            if anchors.contains(anchor) {
+                continue;
+            }
+            let mut others = target_anchors.clone();
+            others.remove(anchor);
+            if try!(is_complete_history(target, *anchor, &others)) {
+                try!(delete_anchor(target, anchor));
+            }
+        }
+        if let Some(lost_head) = lost_head {
+            try!(write_branch_backup(target, &self.branch, &lost_head));
+        }
+        try!(write_branch_head(target, &self.branch, &head));
+        if self.branch == MAIN_BRANCH {
+            let checkpoint_hash = try!(source.read_ref(CHECKPOINT_REF));
+            let checkpoint = try!(read_checkpoint(local));
+            if let (Some(hash), Some(checkpoint)) = (checkpoint_hash, checkpoint) {
+                if checkpoint.head_hash == head {
+                    if !target.exists(&hash) {
+                        try!(target.put(&hash, &try!(source.get(&hash))));
+                        copied += 1;
+                    }
+                    try!(target.write_ref(CHECKPOINT_REF, &hash));
+                }
+            }
+        }
+        Ok(SyncReport {
+            old_head: old_head,
+            new_head: head,
+            copied: copied
+        })
+    }
+}
+
+impl Default for LogSync {
+    fn default() -> Self {
+        LogSync::new()
+    }
+}
+
+/// All objects of the log items reachable from the head.
+///
+/// Childs come before the objects which refer to them and parents before
+/// their log items.  Log items for which known returns true are skipped
+/// together with their history, just like the anchors of the store.  The
+/// objects of new items are always returned, even if some of them are
+/// known.
+///
+/// # Errors
+/// Fails if an object could not be read.
+pub fn reachable_objects<T>(store: &ObjectStore, head: Hash, known: &Fn(&Hash) -> bool)
+        -> Result<Vec<Hash>, LogError>
+        where T: Readable {
+    let anchors = try!(read_anchors(store));
+    // Log items in the order they must be copied
+    let mut items = Vec::new();
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![(head, false)];
+    while let Some((hash, parents_done)) = pending.pop() {
+        if parents_done {
+            items.push(hash);
+            continue;
+        }
+        if known(&hash) || anchors.contains(&hash) || !visited.insert(hash) {
+            continue;
+        }
+        pending.push((hash, true));
+        let (parents, _) = try!(read_stored_links(store, hash));
+        for parent in parents.into_iter().rev() {
+            pending.push((parent, false));
+        }
+    }
+
+    let reader = CollectReader {
+        store: store,
+        hashes: RefCell::new(Vec::new())
+    };
+    for hash in items {
+        let (_, item_hash) = try!(read_stored_links(store, hash));
+        try!(reader.read_child::<T>(&item_hash)
+            .map_err(|err| LogError::from_hashio_error(item_hash, err)));
+        reader.hashes.borrow_mut().push(hash);
+    }
+    let mut seen = BTreeSet::new();
+    Ok(reader.hashes.into_inner().into_iter().filter(|hash| seen.insert(*hash)).collect())
+}
+
+/// Parents and item hash of the stored log item.
+fn read_stored_links(store: &ObjectStore, hash: Hash) -> Result<(Vec<Hash>, Hash), LogError> {
+    let bytes = try!(store.get(&hash).map_err(|err| LogError::from_hashio_error(hash, err)));
+    let mut read: &[u8] = &bytes;
+    read_item_links(&mut read).map_err(|err| LogError::StorageError(hash, err))
+}
+
+/// True if the ancestor is in the stored history of the hash.
+///
+/// The history ends at the anchors of the store.
+pub fn is_stored_ancestor(store: &ObjectStore, ancestor: Hash, hash: Hash) -> Result<bool, LogError> {
+    let anchors = try!(read_anchors(store));
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![hash];
+    while let Some(hash) = pending.pop() {
+        if hash == ancestor {
+            return Ok(true);
+        }
+        if visited.insert(hash) && !anchors.contains(&hash) {
+            pending.extend(try!(read_stored_links(store, hash)).0);
+        }
+    }
+    Ok(false)
+}
+
+/// Check that the stored history of the head is complete.
+///
+/// Every log item and the object of its item must exist, down to the
+/// first entry or the anchors of the store.
+///
+/// # Errors
+/// Fails with EntryNotFound for the first missing object.
+pub fn check_stored_history(store: &ObjectStore, head: Hash) -> Result<(), LogError> {
+    try!(check_history(store, head, &try!(read_anchors(store))));
+    Ok(())
+}
+
+/// Anchors of the store at which the stored history of the head ends.
+///
+/// # Errors
+/// Fails with EntryNotFound if an object of the history is missing.
+pub fn stored_history_anchors(store: &ObjectStore, head: Hash) -> Result<Vec<Hash>, LogError> {
+    check_history(store, head, &try!(read_anchors(store)))
+}
+
+/// True if the history of the hash is stored down to the given anchors.
+fn is_complete_history(store: &ObjectStore, hash: Hash, anchors: &BTreeSet<Hash>)
+        -> Result<bool, LogError> {
+    match check_history(store, hash, anchors) {
+        Ok(_) => Ok(true),
+        Err(LogError::EntryNotFound(_)) => Ok(false),
+        Err(err) => Err(err)
+    }
+}
+
+/// Check that the history of the head is stored down to the anchors.
+///
+/// Returns the anchors which were reached.
+///
+/// # Errors
+/// Fails with EntryNotFound for the first missing object.
+pub fn check_history(store: &ObjectStore, head: Hash, anchors: &BTreeSet<Hash>)
+        -> Result<Vec<Hash>, LogError> {
+    let mut reached = Vec::new();
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![head];
+    while let Some(hash) = pending.pop() {
+        if !visited.insert(hash) {
+            continue;
+        }
+        if anchors.contains(&hash) {
+            reached.push(hash);
+            continue;
+        }
+        let (parents, item_hash) = try!(read_stored_links(store, hash));
+        if !store.exists(&item_hash) {
+            return Err(LogError::EntryNotFound(item_hash));
+        }
+        pending.extend(parents);
+    }
+    Ok(reached)
+}
+
+/// The old head if it's not in the stored history of the new one.
+///
+/// Moving a branch from such a head to the new one loses entries.
+pub fn lost_branch_head(store: &ObjectStore, old_head: Option<Hash>, head: Hash)
+        -> Result<Option<Hash>, LogError> {
+    match old_head {
+        Some(old_head) if !try!(is_stored_ancestor(store, old_head, head)) => Ok(Some(old_head)),
+        _ => Ok(None)
+    }
+}
+
+/// Anchors which cut the stored history of the head down to depth entries.
+///
+/// Works like shallow_anchors on the raw log items.
+fn stored_anchors(store: &ObjectStore, head: Hash, depth: usize, anchors: &BTreeSet<Hash>)
+        -> Result<Vec<Hash>, LogError> {
+    // Same order as Log::history_since
+    let mut hashes = Vec::new();
+    let mut parents = BTreeMap::new();
+    let mut pending = vec![(head, false)];
+    while let Some((hash, parents_done)) = pending.pop() {
+        if parents_done {
+            hashes.push(hash);
+            continue;
+        }
+        if anchors.contains(&hash) || parents.contains_key(&hash) {
+            continue;
+        }
+        pending.push((hash, true));
+        let (item_parents, _) = try!(read_stored_links(store, hash));
+        for parent in item_parents.iter().rev() {
+            pending.push((*parent, false));
+        }
+        parents.insert(hash, item_parents);
+    }
+
+    let depth = depth.max(1);
+    if hashes.len() <= depth {
+        return Ok(Vec::new());
+    }
+    let kept = &hashes[hashes.len() - depth..];
+    let mut result = Vec::new();
+    for hash in kept.iter() {
+        for parent in parents[hash].iter() {
+            if !kept.contains(parent) && !result.contains(parent) {
+                result.push(*parent);
+            }
+        }
+    }
+    Ok(result)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::fsck::*;
+    use super::super::gc::*;
+    use super::super::hashio::*;
+    use super::super::iolog::*;
+    use super::super::log::*;
+    use super::super::store::*;
+    use super::*;
+
+    #[test]
+    fn sync_test() {
+        let dir = TestDirectory::new("synctest");
+        let local_path = dir.join("local");
+        let remote_path = dir.join("remote");
+        let mut log = IOLog::<String>::new(local_path.clone());
+        log.push("one".to_string());
+        log.push("two".to_string());
+        let remote = HashIO::new(remote_path.clone());
+
+        let report = LogSync::new().sync::<String>(&log.hashio, &remote).unwrap();
+        assert_eq!(None, report.old_head);
+        assert_eq!(4, report.copied);
+
+        // Only the new entry is copied
+        let head = log.push("three".to_string());
+        let report = LogSync::new().sync::<String>(&log.hashio, &remote).unwrap();
+        assert_eq!(2, report.copied);
+        assert_eq!(head, report.new_head);
+        let report = LogSync::new().sync::<String>(&log.hashio, &remote).unwrap();
+        assert_eq!(0, report.copied);
+
+        // Somebody else added an entry to the remote repository
+        let remote_head = {
+            let mut remote_log = IOLog::<String>::new(remote_path.clone());
+            assert_eq!(Some(head), remote_log.head_hash());
+            remote_log.push("remote".to_string())
+        };
+        let head = log.push("four".to_string());
+        assert_eq!(Err(LogError::NotAncestor { hash: remote_head, head: head }),
+                   LogSync::new().sync::<String>(&log.hashio, &remote));
+        let report = LogSync::new().force().sync::<String>(&log.hashio, &remote).unwrap();
+        assert_eq!(Some(remote_head), report.old_head);
+        let backups: Vec<String> = remote.store().list_refs().unwrap().into_iter()
+            .filter(|name| name.starts_with("backups/main/")).collect();
+        assert_eq!(1, backups.len());
+        assert_eq!(Some(remote_head), remote.store().read_ref(&backups[0]).unwrap());
+
+        let remote_log = IOLog::<String>::new(remote_path.clone());
+        assert_eq!(Some(head), remote_log.head_hash());
+        assert_eq!(None, verify_log(&remote_log));
+        let fsck_report = fsck::<String>(remote_path).unwrap();
+        assert_eq!(true, fsck_report.is_ok());
+
+        // Shallow clone with the newest two entries
+        let shallow_path = dir.join("shallow");
+        let report = LogSync::new().with_depth(2)
+            .sync::<String>(&log.hashio, &HashIO::new(shallow_path.clone())).unwrap();
+        assert_eq!(4, report.copied);
+        let shallow_log = IOLog::<String>::new(shallow_path.clone());
+        assert_eq!(Some(head), shallow_log.head_hash());
+        assert_eq!(2, LogIteratorRef::from_log(&shallow_log).count());
+        assert_eq!(None, verify_log(&shallow_log));
+        let fsck_report = fsck::<String>(shallow_path.clone()).unwrap();
+        assert_eq!(true, fsck_report.is_ok());
+
+        // Deepen the clone, the anchors are removed and the copied history
+        // survives the garbage collection
+        let shallow = HashIO::new(shallow_path.clone());
+        let report = LogSync::new().sync::<String>(&log.hashio, &shallow).unwrap();
+        assert_eq!(4, report.copied);
+        assert_eq!(0, read_anchors(shallow.store()).unwrap().len());
+        GarbageCollection::new().collect::<String>(&shallow).unwrap();
+        let shallow_log = IOLog::<String>::new(shallow_path.clone());
+        assert_eq!(4, LogIteratorRef::from_log(&shallow_log).count());
+        assert_eq!(None, verify_log(&shallow_log));
+    }
+}
@@ -351,6 +351,10 @@ mod tests {
     use super::rand;
     use super::rand::Rng;
     use std::collections::BTreeMap;
+    use super::super::hash::*;
+    use super::super::hashio::*;
+    use super::super::io::*;
+    use super::super::store::*;
 
     struct TestRand {
         i: usize,
@@ -435,4 +439,28 @@ mod tests {
         assert_eq!(true, task_stat.mark_done("task a".to_string()).is_ok());
         assert_eq!(false, task_stat.mark_done("task b".to_string()).is_ok());
     }
+
+    #[test]
+    fn readable_test() {
+        let store = MemoryStore::new();
+        let task = Task {
+            title: "task a".to_string(),
+            description: "description".to_string(),
+            factor: 1.5
+        };
+        HashIO::with_store(Box::new(store.clone())).put(&task).unwrap();
+
+        // The store provides the title and description
+        let bytes = store.get(&task.as_hash()).unwrap();
+        let mut read: &[u8] = &bytes;
+        assert_eq!(task, Task::read_from(&mut read, &store).unwrap());
+
+        let mut read: &[u8] = &bytes;
+        assert_eq!(true, Task::read_from(&mut read, &NoChilds).is_err());
+
+        let mut bytes: Vec<u8> = Vec::new();
+        task.title.write_to(&mut bytes).unwrap();
+        let mut read: &[u8] = &bytes;
+        assert_eq!(task.title, String::read_from(&mut read, &NoChilds).unwrap());
+    }
 }
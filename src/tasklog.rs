@@ -14,6 +14,7 @@ use hash::*;
 use std::io::{Write, Read};
 use std::io;
 use self::time::{Tm, now};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::error;
 
@@ -22,8 +23,10 @@ pub enum TaskLogError {
     TaskStatError(TaskStatError),
     IOError(io::Error),
     LogError(LogError),
-    NoState
+    NoState,
 
+    /// Both histories of a merge changed the same tasks differently.
+    MergeConflict(Vec<MergeConflict>)
 }
 
 impl fmt::Display for TaskLogError {
@@ -32,7 +35,11 @@ impl fmt::Display for TaskLogError {
             TaskLogError::TaskStatError(ref err) => err.fmt(f),
             TaskLogError::IOError(ref err) => err.fmt(f),
             TaskLogError::LogError(ref err) => err.fmt(f),
-            TaskLogError::NoState => write!(f, "State is none")
+            TaskLogError::NoState => write!(f, "State is none"),
+            TaskLogError::MergeConflict(ref conflicts) => {
+                let titles: Vec<&str> = conflicts.iter().map(|c| &c.title[..]).collect();
+                write!(f, "Merge conflict in tasks: {}", titles.join(", "))
+            }
         }
     }
 }
@@ -43,7 +50,18 @@ impl error::Error for TaskLogError {
             TaskLogError::TaskStatError(ref err) => err.description(),
             TaskLogError::IOError(ref err) => err.description(),
             TaskLogError::LogError(ref err) => err.description(),
-            TaskLogError::NoState => "State is none"
+            TaskLogError::NoState => "State is none",
+            TaskLogError::MergeConflict(_) => "Merge conflict"
+        }
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            TaskLogError::TaskStatError(ref err) => Some(err),
+            TaskLogError::IOError(ref err) => Some(err),
+            TaskLogError::LogError(ref err) => Some(err),
+            TaskLogError::NoState => None,
+            TaskLogError::MergeConflict(_) => None
         }
     }
 }
@@ -73,30 +91,49 @@ pub enum TaskAction {
     ScheduleTask(ActiveTask),
     PoolTask(PooledTask),
     CompleteTask(ActiveTask),
-    ActivateTask(Vec<ActiveTask>)
+    ActivateTask(Vec<ActiveTask>),
+
+    /// Combines the state of two histories.
+    ///
+    /// The actions of both sides are found in the parents of the entry.
+    Merge
+}
+
+/// Version 1 doesn't write an empty child hash for merges anymore.
+impl Versioned for TaskAction {
+    fn version() -> u32 {
+        1
+    }
 }
 
 impl Writable for TaskAction {
     fn write_to<W: Write>(&self, write: &mut W) -> Result<usize, io::Error> {
-        let version = [0u8;4];
+        self.write_to_with(write, HashAlgorithm::Sha3)
+    }
+
+    fn write_to_with<W: Write>(&self, write: &mut W, algorithm: HashAlgorithm)
+            -> Result<usize, io::Error> {
         let mut size : usize = 0;
-        size += try!(write.write(&version));
+        size += try!(write_u32(TaskAction::version(), write));
         match self {
             &TaskAction::ScheduleTask(ref a_task) => {
                 size += try!(write_u8(1, write));
-                size += try!(write_hash(&a_task.as_hash(), write));
+                size += try!(write_hash(&a_task.as_hash_with(algorithm), write));
             },
             &TaskAction::PoolTask(ref p_task) => {
                 size += try!(write_u8(2, write));
-                size += try!(write_hash(&p_task.as_hash(), write));
+                size += try!(write_hash(&p_task.as_hash_with(algorithm), write));
             },
             &TaskAction::CompleteTask(ref a_task) => {
                 size += try!(write_u8(3, write));
-                size += try!(write_hash(&a_task.as_hash(), write));
+                size += try!(write_hash(&a_task.as_hash_with(algorithm), write));
             },
             &TaskAction::ActivateTask(ref a_tasks) => {
                 size += try!(write_u8(4, write));
-                size += try!(write_hash(&a_tasks.as_hash(), write));
+                size += try!(write_hash(&a_tasks.as_hash_with(algorithm), write));
+            },
+            &TaskAction::Merge => {
+                size += try!(write_u8(5, write));
             }
         };
         Ok(size)
@@ -105,47 +142,91 @@ impl Writable for TaskAction {
 
 hashable_for_writable!(TaskAction);
 
+impl Readable for TaskAction {
+    fn read_from<R: Read, C: ChildReader>(read: &mut R, childs: &C)
+            -> Result<TaskAction, HashIOError> {
+        let version = try!(read_u32(read));
+        if version != TaskAction::version() {
+            if version > TaskAction::version() {
+                return Err(HashIOError::UnsupportedVersion(version));
+            }
+            return migrate_task_action(version, read, childs);
+        }
+        let action_type = try!(read_u8(read));
+        let hash = match action_type {
+            5 => Hash::None,
+            _ => try!(read_hash(read))
+        };
+        read_action(action_type, &hash, childs)
+    }
+}
+
+/// Read a TaskAction which was written by an older version.
+///
+/// Version 0 wrote a child hash for every action, even for merges.
+fn migrate_task_action<R, C>(version: u32, read: &mut R, childs: &C)
+        -> Result<TaskAction, HashIOError>
+        where R: Read, C: ChildReader {
+    match version {
+        0 => {
+            let action_type = try!(read_u8(read));
+            let hash = try!(read_hash(read));
+            read_action(action_type, &hash, childs)
+        },
+        _ => Err(HashIOError::UnsupportedVersion(version))
+    }
+}
+
+/// Read the child of the action type and build the action.
+fn read_action<C: ChildReader>(action_type: u8, hash: &Hash, childs: &C)
+        -> Result<TaskAction, HashIOError> {
+    let action = match action_type {
+        1 => {
+            let a_task: ActiveTask = try!(childs.read_child(hash));
+            TaskAction::ScheduleTask(a_task)
+        }
+        2 => {
+            let p_task: PooledTask = try!(childs.read_child(hash));
+            TaskAction::PoolTask(p_task)
+        }
+        3 => {
+            let a_task: ActiveTask = try!(childs.read_child(hash));
+            TaskAction::CompleteTask(a_task)
+        }
+        4 => {
+            let a_tasks: Vec<ActiveTask> = try!(childs.read_child(hash));
+            TaskAction::ActivateTask(a_tasks)
+        }
+        5 => TaskAction::Merge,
+        _ => {
+            return Err(HashIOError::Undefined(format!("Task Action id undefined: {}",
+                                                      action_type)));
+        }
+    };
+    Ok(action)
+}
+
 impl HashIOImpl<TaskAction> for HashIO {
-    fn store_hashable<W>(&self, hashable: &TaskAction, write: &mut W) -> Result<(), HashIOError>
-                    where W: Write {
+    fn store_childs(&self, hashable: &TaskAction) -> Result<(), HashIOError> {
         match hashable {
             &TaskAction::ScheduleTask(ref a_task) => try!(self.put(a_task)),
             &TaskAction::PoolTask(ref p_task) => try!(self.put(p_task)),
             &TaskAction::CompleteTask(ref a_task) => try!(self.put(a_task)),
-            &TaskAction::ActivateTask(ref a_tasks) => try!(self.put(a_tasks))
+            &TaskAction::ActivateTask(ref a_tasks) => try!(self.put(a_tasks)),
+            &TaskAction::Merge => ()
         }
-        try!(hashable.write_to(write));
+        Ok(())
+    }
+
+    fn store_hashable<W>(&self, hashable: &TaskAction, write: &mut W) -> Result<(), HashIOError>
+                    where W: Write {
+        try!(hashable.write_to_with(write, self.algorithm()));
         Ok(())
     }
 
     fn receive_hashable<R>(&self, read: &mut R) -> Result<TaskAction, HashIOError>
                     where R: Read {
-        let _  = try!(read_u32(read)); // version
-        let action_type = try!(read_u8(read));
-        let hash = try!(read_hash(read));
-        let action = match action_type {
-            1 => {
-                let a_task: ActiveTask = try!(self.get(&hash));
-                TaskAction::ScheduleTask(a_task)
-            }
-            2 => {
-                let p_task: PooledTask = try!(self.get(&hash));
-                TaskAction::PoolTask(p_task)
-            }
-            3 => {
-                let a_task: ActiveTask = try!(self.get(&hash));
-                TaskAction::CompleteTask(a_task)
-            }
-            4 => {
-                let a_tasks: Vec<ActiveTask> = try!(self.get(&hash));
-                TaskAction::ActivateTask(a_tasks)
-            }
-            _ => {
-                return Err(HashIOError::Undefined(format!("Task Action id undefined: {}",
-                                                          action_type)));
-            }
-        };
-        Ok(action)
+        TaskAction::read_from(read, self)
     }
 }
 
@@ -157,12 +238,88 @@ tbd_model!(TaskLogEntry, [
     [state: TaskStat]
 ]);
 
+/// Last change of a task on both sides of a merge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeConflict {
+    pub title: String,
+
+    /// Change on the current branch.
+    pub ours: TaskAction,
+
+    /// Change in the merged history.
+    pub theirs: TaskAction
+}
+
+/// How to merge tasks which were changed differently on both sides.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeResolution {
+    /// Fail with MergeConflict and don't change anything.
+    Abort,
+
+    /// Skip the conflicting changes of the merged history.
+    KeepOurs,
+
+    /// Skip the conflicting changes of the current branch.
+    KeepTheirs
+}
+
+/// Result of a successful merge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeReport {
+    /// New head of the log.
+    pub hash: Hash,
+
+    /// Conflicts which were resolved by the MergeResolution.
+    pub conflicts: Vec<MergeConflict>
+}
+
+/// Split the action into one change for each task it affects.
+fn task_changes(action: &TaskAction) -> Vec<(String, TaskAction)> {
+    match action {
+        &TaskAction::ScheduleTask(ref a_task) | &TaskAction::CompleteTask(ref a_task) =>
+            vec![(a_task.task.title.clone(), action.clone())],
+        &TaskAction::PoolTask(ref p_task) => vec![(p_task.task.title.clone(), action.clone())],
+        &TaskAction::ActivateTask(ref a_tasks) => a_tasks.iter()
+            .map(|a_task| (a_task.task.title.clone(), TaskAction::ActivateTask(vec![a_task.clone()])))
+            .collect(),
+        &TaskAction::Merge => Vec::new()
+    }
+}
+
+/// Apply a single task change at the reference time of its entry.
+///
+/// Completing a task which is not active is ignored, since the other
+/// side of a merge might have completed it already.
+fn apply_change(state: &mut TaskStat, ref_tm: Tm, action: &TaskAction) {
+    state.ref_tm = ref_tm;
+    match action {
+        &TaskAction::ScheduleTask(ref a_task) => {
+            state.active.insert(a_task.task.title.clone(), a_task.clone());
+        },
+        &TaskAction::PoolTask(ref p_task) => {
+            state.pool.insert(p_task.task.title.clone(), p_task.clone());
+        },
+        &TaskAction::CompleteTask(ref a_task) => {
+            state.mark_done(a_task.task.title.clone()).ok();
+        },
+        &TaskAction::ActivateTask(ref a_tasks) => {
+            for a_task in a_tasks.iter() {
+                state.active.insert(a_task.task.title.clone(), a_task.clone());
+            }
+        },
+        &TaskAction::Merge => ()
+    }
+}
+
 pub struct TaskLog {
     pub log: IOLog<TaskLogEntry>,
     pub state: TaskStat
 }
 
 impl TaskLog {
+    /// Open the task log in the given directory.
+    ///
+    /// Like IOLog::new, the pack of the directory is used if it has one.
     pub fn new(path: String) -> TaskLog {
         TaskLog {
             log: IOLog::new(path),
@@ -170,6 +327,14 @@ impl TaskLog {
         }
     }
 
+    /// Create a task log which uses the given HashIO as storage.
+    pub fn with_hashio(hashio: HashIO) -> TaskLog {
+        TaskLog {
+            log: IOLog::with_hashio(hashio),
+            state: TaskStat::empty_task_stat()
+        }
+    }
+
     pub fn load_head(&mut self) -> Result<(), TaskLogError> {
         let stat_hash = self.log.head_hash();
         match stat_hash {
@@ -182,16 +347,170 @@ impl TaskLog {
         Ok(())
     }
 
-    pub fn store_state(&mut self, action: TaskAction) -> Result<(), TaskLogError> {
+    /// Add the action and the current state to the log.
+    ///
+    /// Returns the hash of the new log entry.
+    pub fn store_state(&mut self, action: TaskAction) -> Result<Hash, TaskLogError> {
         let tm = now();
         let entry = TaskLogEntry {
             timestamp: tm,
             action: action,
             state: self.state.clone()
         };
-        self.log.push(entry);
+        let hash = try!(self.log.try_push(entry));
+        Ok(hash)
+    }
+
+    /// Name of the branch the actions are stored on.
+    pub fn branch(&self) -> &str {
+        self.log.branch()
+    }
+
+    /// Names of all branches.
+    pub fn list_branches(&self) -> Result<Vec<String>, TaskLogError> {
+        Ok(try!(self.log.list_branches()))
+    }
+
+    /// Create a branch of the current state.
+    ///
+    /// Switch to it to try out a plan, the history of the other branches
+    /// stays untouched.
+    pub fn create_branch(&self, name: &str) -> Result<(), TaskLogError> {
+        try!(self.log.create_branch(name));
+        Ok(())
+    }
+
+    /// Continue on another branch and load its state.
+    pub fn switch_branch(&mut self, name: &str) -> Result<(), TaskLogError> {
+        try!(self.log.switch_branch(name));
+        self.load_head()
+    }
+
+    /// Throw away a branch which is not needed anymore.
+    pub fn delete_branch(&self, name: &str) -> Result<(), TaskLogError> {
+        try!(self.log.delete_branch(name));
         Ok(())
     }
+
+    /// Task changes of all entries since the base up to the head.
+    ///
+    /// Each change comes with the reference time of its entry.
+    fn changes_since(&self, base: Option<Hash>, head: Hash)
+            -> Result<Vec<(String, Tm, TaskAction)>, TaskLogError> {
+        let mut result = Vec::new();
+        for hash in try!(self.log.history_since(base, head)) {
+            let entry = try!(self.log.get(hash));
+            for (title, action) in task_changes(&entry.action) {
+                result.push((title, entry.state.ref_tm, action));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Merge the history of another head into the current branch.
+    ///
+    /// The task actions of both sides since their common ancestor are
+    /// replayed on the state of the ancestor.  If a task was changed on
+    /// both sides and the last changes differ, the resolution decides
+    /// which side wins.  Merging a head which is already in the history
+    /// does nothing and an outdated branch is just fast forwarded.
+    ///
+    /// # Errors
+    /// Fails with MergeConflict if there are conflicts and the resolution
+    /// is Abort.  The log is not changed in this case.
+    pub fn merge(&mut self, other: Hash, resolution: MergeResolution)
+            -> Result<MergeReport, TaskLogError> {
+        let head = self.log.head_hash();
+        if let Some(head) = head {
+            if try!(self.log.is_ancestor(other, head)) {
+                return Ok(MergeReport { hash: head, conflicts: Vec::new() });
+            }
+        }
+        let fast_forward = match head {
+            None => true,
+            Some(head) => try!(self.log.is_ancestor(head, other))
+        };
+        if fast_forward {
+            try!(self.log.reset_head(&other));
+            try!(self.log.write_head().map_err(|err| LogError::StorageError(other, err)));
+            try!(self.load_head());
+            return Ok(MergeReport { hash: other, conflicts: Vec::new() });
+        }
+        let head = head.unwrap();
+
+        let ancestor = try!(self.log.common_ancestor(head, other));
+        let ours = try!(self.changes_since(ancestor, head));
+        let theirs = try!(self.changes_since(ancestor, other));
+        let last_ours: BTreeMap<&str, &TaskAction> = ours.iter()
+            .map(|&(ref title, _, ref action)| (&title[..], action)).collect();
+        let last_theirs: BTreeMap<&str, &TaskAction> = theirs.iter()
+            .map(|&(ref title, _, ref action)| (&title[..], action)).collect();
+        let mut conflicts = Vec::new();
+        for (title, our_action) in last_ours.iter() {
+            if let Some(their_action) = last_theirs.get(title) {
+                if our_action != their_action {
+                    conflicts.push(MergeConflict {
+                        title: title.to_string(),
+                        ours: (*our_action).clone(),
+                        theirs: (*their_action).clone()
+                    });
+                }
+            }
+        }
+        if !conflicts.is_empty() && resolution == MergeResolution::Abort {
+            return Err(TaskLogError::MergeConflict(conflicts));
+        }
+
+        let mut state = match ancestor {
+            None => TaskStat::empty_task_stat(),
+            Some(hash) => try!(self.log.get(hash)).state
+        };
+        let is_conflict = |title: &str| conflicts.iter().any(|c| c.title == title);
+        for &(ref title, ref_tm, ref action) in ours.iter() {
+            if resolution != MergeResolution::KeepTheirs || !is_conflict(title) {
+                apply_change(&mut state, ref_tm, action);
+            }
+        }
+        for &(ref title, ref_tm, ref action) in theirs.iter() {
+            if resolution != MergeResolution::KeepOurs || !is_conflict(title) {
+                apply_change(&mut state, ref_tm, action);
+            }
+        }
+        state.update_ref_tm();
+        let entry = TaskLogEntry {
+            timestamp: now(),
+            action: TaskAction::Merge,
+            state: state.clone()
+        };
+        let hash = try!(self.log.try_merge(&[other], entry));
+        self.state = state;
+        Ok(MergeReport {
+            hash: hash,
+            conflicts: conflicts
+        })
+    }
+
+    /// Merge the head of the branch into the current branch.
+    ///
+    /// See merge.
+    pub fn merge_branch(&mut self, name: &str, resolution: MergeResolution)
+            -> Result<MergeReport, TaskLogError> {
+        match try!(self.log.branch_head(name)) {
+            None => Err(TaskLogError::LogError(LogError::BranchNotFound(name.to_string()))),
+            Some(hash) => self.merge(hash, resolution)
+        }
+    }
+
+    /// Store the action or go back to the previous state if that fails.
+    fn commit(&mut self, action: TaskAction, previous: TaskStat) -> Result<Hash, TaskLogError> {
+        match self.store_state(action) {
+            Ok(hash) => Ok(hash),
+            Err(err) => {
+                self.state = previous;
+                Err(err)
+            }
+        }
+    }
 }
 
 
@@ -202,33 +521,37 @@ impl TaskStatTrait for TaskLog {
 
     fn add_active_task(&mut self, title: String, description: String,
                        factor: f32, due_days: i16) -> Result<ActiveTask, Self::Error> {
+        let previous = self.state.clone();
         self.state.update_ref_tm();
         let a_task = try!(self.state.add_active_task(title, description, factor, due_days));
-        try!(self.store_state(TaskAction::ScheduleTask(a_task.clone())));
+        try!(self.commit(TaskAction::ScheduleTask(a_task.clone()), previous));
         Ok(a_task)
     }
 
     fn add_pooled_task(&mut self, title: String, description: String,
                        factor: f32, propability: f32,
                        cool_down: i16, due_days: i16) -> Result<PooledTask, Self::Error> {
+        let previous = self.state.clone();
         self.state.update_ref_tm();
         let p_task = try!(self.state.add_pooled_task(title, description, factor,
                                 propability, cool_down, due_days));
-        try!(self.store_state(TaskAction::PoolTask(p_task.clone())));
+        try!(self.commit(TaskAction::PoolTask(p_task.clone()), previous));
         Ok(p_task)
     }
 
     fn activate<R: rand::Rng>(&mut self, rng: &mut R) -> Result<Vec<ActiveTask>, Self::Error> {
+        let previous = self.state.clone();
         self.state.update_ref_tm();
         let a_tasks = try!(self.state.activate(rng));
-        try!(self.store_state(TaskAction::ActivateTask(a_tasks.clone())));
+        try!(self.commit(TaskAction::ActivateTask(a_tasks.clone()), previous));
         Ok(a_tasks)
     }
 
     fn mark_done(&mut self, title: String) -> Result<ActiveTask, Self::Error> {
+        let previous = self.state.clone();
         self.state.update_ref_tm();
         let a_task = try!(self.state.mark_done(title));
-        try!(self.store_state(TaskAction::CompleteTask(a_task.clone())));
+        try!(self.commit(TaskAction::CompleteTask(a_task.clone()), previous));
         Ok(a_task)
     }
 
@@ -241,4 +564,144 @@ impl TaskStatTrait for TaskLog {
         let p_tasks = try!(self.state.all_pooled());
         Ok(p_tasks)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::store::*;
+    #[test]
+    fn memory_session_test() {
+        let store = MemoryStore::new();
+        {
+            let mut task_log = TaskLog::with_hashio(HashIO::with_store(Box::new(store.clone())));
+            task_log.add_active_task("task a".to_string(), "".to_string(), 1.0, 3).unwrap();
+            task_log.add_active_task("task b".to_string(), "".to_string(), 1.0, 3).unwrap();
+            task_log.mark_done("task a".to_string()).unwrap();
+        }
+
+        let mut task_log = TaskLog::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        task_log.load_head().unwrap();
+        let actives = task_log.all_actives().unwrap();
+        assert_eq!(1, actives.len());
+        assert_eq!("task b", actives[0].task.title);
+        assert_eq!(3, LogIteratorHash::from_log(&task_log.log).count());
+    }
+
+    #[test]
+    fn full_store_test() {
+        let store = FullStore::default();
+        let mut task_log = TaskLog::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        task_log.add_active_task("task a".to_string(), "".to_string(), 1.0, 3).unwrap();
+        let head = task_log.log.head_hash();
+
+        store.full.set(true);
+        assert_eq!(true, task_log.add_active_task("task b".to_string(), "".to_string(),
+                                                  1.0, 3).is_err());
+        assert_eq!(true, task_log.mark_done("task a".to_string()).is_err());
+        // Neither the log nor the state changed
+        assert_eq!(head, task_log.log.head_hash());
+        assert_eq!(1, task_log.all_actives().unwrap().len());
+        assert_eq!("task a", task_log.all_actives().unwrap()[0].task.title);
+
+        store.full.set(false);
+        task_log.mark_done("task a".to_string()).unwrap();
+        assert_eq!(0, task_log.all_actives().unwrap().len());
+        assert_eq!(2, LogIteratorHash::from_log(&task_log.log).count());
+    }
+
+    #[test]
+    fn action_migration_test() {
+        let hashio = HashIO::with_store(Box::new(MemoryStore::new()));
+        let mut task_log = TaskLog::with_hashio(HashIO::with_store(Box::new(MemoryStore::new())));
+        task_log.add_active_task("task a".to_string(), "".to_string(), 1.0, 3).unwrap();
+        let a_task = task_log.all_actives().unwrap().remove(0);
+        hashio.put(&a_task).unwrap();
+
+        // Version 0 always wrote a child hash
+        let mut bytes = Vec::new();
+        write_u32(0, &mut bytes).unwrap();
+        write_u8(1, &mut bytes).unwrap();
+        write_hash(&a_task.as_hash(), &mut bytes).unwrap();
+        write_u32(0, &mut bytes).unwrap();
+        write_u8(5, &mut bytes).unwrap();
+        write_hash(&Hash::None, &mut bytes).unwrap();
+        let mut read: &[u8] = &bytes;
+        assert_eq!(TaskAction::ScheduleTask(a_task.clone()),
+                   TaskAction::read_from(&mut read, &hashio).unwrap());
+        assert_eq!(TaskAction::Merge, TaskAction::read_from(&mut read, &hashio).unwrap());
+        assert_eq!(0, read.len());
+
+        let mut bytes = Vec::new();
+        TaskAction::Merge.write_to(&mut bytes).unwrap();
+        assert_eq!(5, bytes.len());
+        let mut bytes = Vec::new();
+        write_u32(2, &mut bytes).unwrap();
+        let mut read: &[u8] = &bytes;
+        match TaskAction::read_from(&mut read, &hashio) {
+            Err(HashIOError::UnsupportedVersion(2)) => (),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn branch_test() {
+        let store = MemoryStore::new();
+        let mut task_log = TaskLog::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        task_log.add_active_task("task a".to_string(), "".to_string(), 1.0, 3).unwrap();
+        task_log.create_branch("plan").unwrap();
+        task_log.switch_branch("plan").unwrap();
+        task_log.mark_done("task a".to_string()).unwrap();
+        task_log.add_active_task("task b".to_string(), "".to_string(), 1.0, 3).unwrap();
+        assert_eq!("task b", task_log.all_actives().unwrap()[0].task.title);
+
+        // The plan is discarded
+        task_log.switch_branch("main").unwrap();
+        task_log.delete_branch("plan").unwrap();
+        assert_eq!(1, task_log.all_actives().unwrap().len());
+        assert_eq!("task a", task_log.all_actives().unwrap()[0].task.title);
+        assert_eq!(vec!["main".to_string()], task_log.list_branches().unwrap());
+    }
+
+    #[test]
+    fn merge_test() {
+        let store = MemoryStore::new();
+        let mut ours = TaskLog::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        ours.add_active_task("task a".to_string(), "".to_string(), 1.0, 3).unwrap();
+        ours.add_active_task("task b".to_string(), "".to_string(), 1.0, 3).unwrap();
+        ours.create_branch("other").unwrap();
+
+        let mut theirs = TaskLog::with_hashio(HashIO::with_store(Box::new(store.clone())));
+        theirs.switch_branch("other").unwrap();
+        theirs.add_active_task("task a".to_string(), "rescheduled".to_string(), 1.0, 5).unwrap();
+        theirs.add_active_task("task d".to_string(), "".to_string(), 1.0, 3).unwrap();
+
+        ours.mark_done("task a".to_string()).unwrap();
+        ours.add_active_task("task c".to_string(), "".to_string(), 1.0, 3).unwrap();
+        let head = ours.log.head_hash();
+
+        match ours.merge_branch("other", MergeResolution::Abort) {
+            Err(TaskLogError::MergeConflict(ref conflicts)) => {
+                assert_eq!(1, conflicts.len());
+                assert_eq!("task a", conflicts[0].title);
+            },
+            other => panic!("Unexpected result: {:?}", other)
+        }
+        assert_eq!(head, ours.log.head_hash());
+
+        let report = ours.merge_branch("other", MergeResolution::KeepOurs).unwrap();
+        assert_eq!(1, report.conflicts.len());
+        let titles = |task_log: &TaskLog| -> Vec<String> {
+            task_log.all_actives().unwrap().into_iter().map(|a| a.task.title).collect()
+        };
+        assert_eq!(vec!["task b", "task c", "task d"], titles(&ours));
+        let other_head = ours.log.branch_head("other").unwrap().unwrap();
+        assert_eq!(true, ours.log.is_ancestor(other_head, report.hash).unwrap());
+
+        // The other side only needs to fast forward
+        let report = theirs.merge_branch("main", MergeResolution::Abort).unwrap();
+        assert_eq!(Some(report.hash), ours.log.head_hash());
+        assert_eq!(titles(&ours), titles(&theirs));
+        assert_eq!(report, theirs.merge_branch("main", MergeResolution::Abort).unwrap());
+    }
+}